@@ -0,0 +1,209 @@
+use serde_json::json;
+use std::{
+    error::Error,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, IsTerminal},
+    path::Path,
+    time::SystemTime,
+};
+
+use crate::{
+    backup::{ImportOptions, Redaction, export_accounts, import_accounts},
+    cli::{AccountTarget, Command},
+    config::AppConfig,
+    models::{
+        account::{Account, Ban},
+        account_view::AccountAdminView,
+    },
+    storage::{AccountPatch, AccountStore},
+    utilities::millis_timestamps::SystemTimeMillisTimestamps,
+};
+
+const DAY_MILLIS: i64 = 24 * 60 * 60 * 1000;
+
+async fn find_account(
+    store: &dyn AccountStore,
+    target: &AccountTarget,
+) -> Result<Account, Box<dyn Error>> {
+    let account = match (&target.account_name, target.member_number) {
+        (Some(account_name), _) => store.find_by_name(&account_name.to_uppercase()).await?,
+        (None, Some(member_number)) => store.find_by_member_number(member_number).await?,
+        (None, None) => None,
+    };
+    account.ok_or_else(|| "No such account".into())
+}
+
+async fn update_account(
+    store: &dyn AccountStore,
+    account: &Account,
+    field: &str,
+    value: serde_json::Value,
+) -> Result<(), Box<dyn Error>> {
+    let mut patch = AccountPatch::new();
+    patch.insert(field.into(), value);
+    store.update(&account.account_name, patch).await?;
+    Ok(())
+}
+
+/// Takes the new password from a file, a terminal prompt or the first line of stdin, never
+/// from the command line where other users and the shell history would see it
+fn read_new_password(password_file: Option<&Path>) -> Result<String, Box<dyn Error>> {
+    let password = match password_file {
+        Some(path) => fs::read_to_string(path)?
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .to_string(),
+        None if io::stdin().is_terminal() => {
+            let password = rpassword::prompt_password("New password: ")?;
+            if rpassword::prompt_password("Repeat it: ")? != password {
+                return Err("The passwords don't match".into());
+            }
+            password
+        }
+        None => {
+            let mut line = String::new();
+            io::stdin().lock().read_line(&mut line)?;
+            line
+        }
+    };
+    Ok(password.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Runs an account management command against the configured storage. Changes reach players
+/// who are online at their next login.
+pub async fn run(
+    store: &dyn AccountStore,
+    config: &AppConfig,
+    command: Command,
+) -> Result<(), Box<dyn Error>> {
+    match command {
+        Command::MigrateAccounts { dry_run } => {
+            print!("{}", store.migrate_accounts(dry_run).await?);
+        }
+        Command::Export {
+            output,
+            redact_emails,
+            redact_passwords,
+        } => {
+            let redaction = Redaction {
+                emails: redact_emails,
+                passwords: redact_passwords,
+            };
+            let report = match output {
+                Some(path) => export_accounts(store, File::create(path)?, redaction).await?,
+                None => export_accounts(store, io::stdout().lock(), redaction).await?,
+            };
+            eprint!("{report}");
+            if !report.unreadable.is_empty() {
+                return Err("Some accounts could not be exported".into());
+            }
+        }
+        Command::Import {
+            input,
+            upsert,
+            on_member_number_conflict,
+            dry_run,
+        } => {
+            let options = ImportOptions {
+                upsert,
+                member_number_conflict: on_member_number_conflict,
+                dry_run,
+            };
+            let report = match input {
+                Some(path) => {
+                    let input = BufReader::new(File::open(path)?);
+                    import_accounts(store, config, input, options).await?
+                }
+                None => import_accounts(store, config, io::stdin().lock(), options).await?,
+            };
+            print!("{report}");
+        }
+        Command::Lookup(target) => {
+            let account = find_account(store, &target).await?;
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&AccountAdminView::from(&account))?
+            );
+        }
+        Command::ResetPassword {
+            target,
+            password_file,
+        } => {
+            let account = find_account(store, &target).await?;
+            let password = read_new_password(password_file.as_deref())?;
+            if !config.account_password_regex.is_match(&password) {
+                return Err("Invalid password".into());
+            }
+            let hash = Account::hash_password(&password, config.bcrypt_cost)?;
+            update_account(store, &account, "Password", json!(hash)).await?;
+            println!("Password of {} reset", account.account_name);
+        }
+        Command::SetMoney { target, money } => {
+            let account = find_account(store, &target).await?;
+            update_account(store, &account, "Money", json!(money)).await?;
+            println!("Money of {} set to {money}", account.account_name);
+        }
+        Command::GrantRole { target, role } => {
+            let mut account = find_account(store, &target).await?;
+            if account.roles.contains(&role) {
+                println!("{} already is {role:?}", account.account_name);
+                return Ok(());
+            }
+            account.roles.push(role);
+            update_account(store, &account, "Roles", json!(account.roles)).await?;
+            println!("{} is now {role:?}", account.account_name);
+        }
+        Command::RevokeRole { target, role } => {
+            let mut account = find_account(store, &target).await?;
+            if !account.roles.contains(&role) {
+                println!("{} isn't {role:?}", account.account_name);
+                return Ok(());
+            }
+            account.roles.retain(|r| *r != role);
+            update_account(store, &account, "Roles", json!(account.roles)).await?;
+            println!("{} is no longer {role:?}", account.account_name);
+        }
+        Command::Ban {
+            target,
+            reason,
+            days,
+        } => {
+            let account = find_account(store, &target).await?;
+            let now = SystemTime::now().get_timestamp_in_milliseconds();
+            let ban = Ban {
+                reason,
+                since: now,
+                until: days.map(|days| now + i64::from(days) * DAY_MILLIS),
+            };
+            update_account(store, &account, "Ban", json!(ban)).await?;
+            println!("{} banned", account.account_name);
+        }
+        Command::Unban(target) => {
+            let account = find_account(store, &target).await?;
+            update_account(store, &account, "Ban", json!(null)).await?;
+            println!("{} unbanned", account.account_name);
+        }
+        Command::Rename { target, name } => {
+            if !config.character_name_regex.is_match(&name) {
+                return Err("Invalid character name".into());
+            }
+            let account = find_account(store, &target).await?;
+            update_account(store, &account, "Name", json!(name)).await?;
+            println!("{} renamed to {name}", account.account_name);
+        }
+        Command::ReleaseOwnership(target) => {
+            let account = find_account(store, &target).await?;
+            if account.ownership.is_none() {
+                println!("{} has no owner", account.account_name);
+                return Ok(());
+            }
+            update_account(store, &account, "Ownership", json!(null)).await?;
+            println!("{} released from ownership", account.account_name);
+        }
+        Command::Serve => unreachable!("serve isn't an admin command"),
+        #[cfg(feature = "sqlite")]
+        Command::MigrateMongoToSqlite => unreachable!("the migration doesn't use a store"),
+    }
+    Ok(())
+}
@@ -0,0 +1,398 @@
+use serde_json::{Map, Value};
+use std::{
+    error::Error,
+    fmt,
+    io::{BufRead, Write},
+};
+
+use crate::{
+    common::protocol::AccountUpdateRequest,
+    config::AppConfig,
+    models::{account::Account, account_migrations::migrate_account},
+    storage::{AccountPatch, AccountStore, StoreError},
+};
+
+const EXPORT_PAGE_SIZE: usize = 500;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Redaction {
+    /// Drops emails along with their verification state
+    pub emails: bool,
+    /// Drops password hashes and two-factor secrets
+    pub passwords: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct ExportReport {
+    pub exported: usize,
+    /// Accounts left out of the export because they couldn't be read
+    pub unreadable: Vec<String>,
+}
+
+impl fmt::Display for ExportReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} accounts exported, {} unreadable",
+            self.exported,
+            self.unreadable.len()
+        )?;
+        if !self.unreadable.is_empty() {
+            writeln!(f, "  Unreadable: {}", self.unreadable.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes every account as one JSON object per line, sorted by account name. Accounts that
+/// can't be read are left out and listed in the report.
+pub async fn export_accounts(
+    store: &dyn AccountStore,
+    mut output: impl Write,
+    redaction: Redaction,
+) -> Result<ExportReport, Box<dyn Error>> {
+    let mut report = ExportReport::default();
+    let mut after = None;
+    loop {
+        let page = store.list_page(after.clone(), EXPORT_PAGE_SIZE).await?;
+        if page.last.is_none() {
+            break;
+        }
+        after = page.last;
+        report.unreadable.extend(page.unreadable);
+        for mut account in page.accounts {
+            if redaction.emails {
+                account.email = None;
+                account.email_verified = false;
+                account.email_verification = None;
+            }
+            if redaction.passwords {
+                account.password = None;
+                account.two_factor = None;
+            }
+            serde_json::to_writer(&mut output, &account)?;
+            output.write_all(b"\n")?;
+            report.exported += 1;
+        }
+    }
+    output.flush()?;
+    Ok(report)
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum MemberNumberConflict {
+    /// Leave the imported account out
+    #[default]
+    Skip,
+    /// Give the imported account a new member number, friend lists pointing to the old one
+    /// won't follow
+    Renumber,
+    /// Stop the import
+    Fail,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ImportOptions {
+    /// Overwrite accounts that already exist with the same account name
+    pub upsert: bool,
+    pub member_number_conflict: MemberNumberConflict,
+    /// Check every record without writing anything
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub inserted: usize,
+    pub updated: usize,
+    pub renumbered: usize,
+    /// Line number and reason of every record left out
+    pub skipped: Vec<(usize, String)>,
+}
+
+impl fmt::Display for ImportReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let verb = if self.dry_run { "would be" } else { "were" };
+        writeln!(
+            f,
+            "{} accounts {verb} inserted, {} updated, {} renumbered, {} skipped",
+            self.inserted,
+            self.updated,
+            self.renumbered,
+            self.skipped.len()
+        )?;
+        for (line, reason) in &self.skipped {
+            writeln!(f, "  line {line}: {reason}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads a record the way stored accounts and client updates are read
+fn parse_account(config: &AppConfig, line: &str) -> Result<Account, String> {
+    let mut fields: Map<String, Value> =
+        serde_json::from_str(line).map_err(|e| format!("invalid JSON: {e}"))?;
+    migrate_account(&mut fields);
+    let fields = Value::Object(fields);
+
+    let account: Account =
+        serde_json::from_value(fields.clone()).map_err(|e| format!("invalid account: {e}"))?;
+    if !config.account_name_regex.is_match(&account.account_name)
+        || account.account_name != account.account_name.to_uppercase()
+    {
+        return Err(format!("invalid account name {:?}", account.account_name));
+    }
+    if !config.character_name_regex.is_match(&account.name) {
+        return Err(format!("invalid character name {:?}", account.name));
+    }
+    if let Some(email) = &account.email
+        && !email.is_empty()
+        && (!Account::is_valid_mail(email) || *email != Account::normalize_mail(email))
+    {
+        return Err(format!("invalid email {email:?}"));
+    }
+
+    // Every field a client may send goes through the same checks as `AccountUpdate`
+    let mut update: AccountUpdateRequest =
+        serde_json::from_value(fields).map_err(|e| format!("invalid account: {e}"))?;
+    let errors = update.validate();
+    if !errors.is_empty() {
+        return Err(format!("invalid fields {errors:?}"));
+    }
+    Ok(account)
+}
+
+fn account_patch(account: &Account) -> Result<AccountPatch, StoreError> {
+    match serde_json::to_value(account) {
+        Ok(Value::Object(mut fields)) => {
+            fields.remove("ID");
+            fields.remove("AccountName");
+            // Redacted exports keep the credentials of the account they overwrite, and the
+            // verification state goes with the kept email
+            if fields.get("Email").is_some_and(Value::is_null) {
+                fields.remove("EmailVerified");
+            }
+            for redacted in ["Password", "Email", "EmailVerification", "TwoFactor"] {
+                if fields.get(redacted).is_some_and(Value::is_null) {
+                    fields.remove(redacted);
+                }
+            }
+            Ok(fields)
+        }
+        Ok(_) => Err(StoreError::Backend("account is not an object".to_string())),
+        Err(e) => Err(StoreError::Backend(e.to_string())),
+    }
+}
+
+/// Loads accounts written by `export_accounts`, one record at a time
+pub async fn import_accounts(
+    store: &dyn AccountStore,
+    config: &AppConfig,
+    input: impl BufRead,
+    options: ImportOptions,
+) -> Result<ImportReport, Box<dyn Error>> {
+    let mut report = ImportReport {
+        dry_run: options.dry_run,
+        ..Default::default()
+    };
+    for (index, line) in input.lines().enumerate() {
+        let line_number = index + 1;
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut account = match parse_account(config, &line) {
+            Ok(account) => account,
+            Err(reason) => {
+                report.skipped.push((line_number, reason));
+                continue;
+            }
+        };
+
+        let existing = store.find_by_name(&account.account_name).await?;
+        if existing.is_some() && !options.upsert {
+            report.skipped.push((
+                line_number,
+                format!("{} already exists", account.account_name),
+            ));
+            continue;
+        }
+
+        let holder = store.find_by_member_number(account.member_number).await?;
+        let mut renumbered = false;
+        if let Some(holder) = holder
+            && holder.account_name != account.account_name
+        {
+            let conflict = format!(
+                "member number {} of {} belongs to {}",
+                account.member_number, account.account_name, holder.account_name
+            );
+            match options.member_number_conflict {
+                MemberNumberConflict::Skip => {
+                    report.skipped.push((line_number, conflict));
+                    continue;
+                }
+                MemberNumberConflict::Fail => {
+                    return Err(format!("line {line_number}: {conflict}").into());
+                }
+                MemberNumberConflict::Renumber => {
+                    if !options.dry_run {
+                        account.member_number = store.next_member_number().await?;
+                    }
+                    renumbered = true;
+                }
+            }
+        }
+
+        let result = match (&existing, options.dry_run) {
+            (_, true) => Ok(()),
+            (Some(_), false) => {
+                store
+                    .update(&account.account_name, account_patch(&account)?)
+                    .await
+            }
+            (None, false) => store.insert(&account).await,
+        };
+        match result {
+            Ok(()) => {
+                if existing.is_some() {
+                    report.updated += 1;
+                } else {
+                    report.inserted += 1;
+                }
+                if renumbered {
+                    report.renumbered += 1;
+                }
+            }
+            Err(StoreError::Duplicate(e)) => report.skipped.push((line_number, e)),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::test_config,
+        models::account::{EmailVerification, TwoFactor},
+        storage::memory::MemoryAccountStore,
+    };
+
+    const PASSWORD: &str = "Secret123";
+
+    async fn store_with_alice() -> MemoryAccountStore {
+        let store = MemoryAccountStore::new();
+        let alice = Account {
+            account_name: "ALICE".to_string(),
+            name: "Alice".to_string(),
+            member_number: 1,
+            password: Some(Account::hash_password(PASSWORD, 4).unwrap()),
+            email: Some("alice@example.com".to_string()),
+            email_verified: true,
+            email_verification: Some(EmailVerification {
+                token_hash: "hash".to_string(),
+                expires: 0,
+            }),
+            two_factor: Some(TwoFactor {
+                secret: "JBSWY3DPEHPK3PXP".to_string(),
+                enabled: true,
+                recovery_codes: vec![],
+                last_used_step: None,
+            }),
+            money: 250,
+            ..Default::default()
+        };
+        store.insert(&alice).await.unwrap();
+        store
+    }
+
+    async fn export(store: &dyn AccountStore, redaction: Redaction) -> Vec<u8> {
+        let mut output = vec![];
+        let report = export_accounts(store, &mut output, redaction)
+            .await
+            .unwrap();
+        assert_eq!(report.exported, 1);
+        assert!(report.unreadable.is_empty());
+        output
+    }
+
+    async fn import(store: &dyn AccountStore, input: &[u8], upsert: bool) -> ImportReport {
+        let options = ImportOptions {
+            upsert,
+            ..Default::default()
+        };
+        import_accounts(store, &test_config(), input, options)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn full_export_logs_in_after_import() {
+        let source = store_with_alice().await;
+        let exported = export(&source, Redaction::default()).await;
+
+        let target = MemoryAccountStore::new();
+        let report = import(&target, &exported, false).await;
+        assert_eq!(report.inserted, 1, "{report}");
+
+        let alice = target.find_by_name("ALICE").await.unwrap().unwrap();
+        assert!(alice.verify_password(PASSWORD).unwrap());
+        assert_eq!(alice.money, 250);
+        assert!(alice.two_factor.is_some_and(|t| t.enabled));
+    }
+
+    #[tokio::test]
+    async fn redacted_export_imported_fresh_can_not_log_in() {
+        let source = store_with_alice().await;
+        let redaction = Redaction {
+            emails: true,
+            passwords: true,
+        };
+        let exported = export(&source, redaction).await;
+        let text = String::from_utf8(exported.clone()).unwrap();
+        assert!(!text.contains("alice@example.com"));
+        assert!(!text.contains("$2b$"));
+        assert!(!text.contains("JBSWY3DPEHPK3PXP"));
+
+        let target = MemoryAccountStore::new();
+        let report = import(&target, &exported, false).await;
+        assert_eq!(report.inserted, 1, "{report}");
+
+        let alice = target.find_by_name("ALICE").await.unwrap().unwrap();
+        assert_eq!(alice.password, None);
+        assert!(!alice.verify_password(PASSWORD).unwrap());
+        assert!(!alice.verify_password("").unwrap());
+        assert_eq!(alice.email, None);
+        assert!(!alice.email_verified);
+        assert!(alice.two_factor.is_none());
+    }
+
+    #[tokio::test]
+    async fn redacted_export_upserted_keeps_the_credentials() {
+        let store = store_with_alice().await;
+        let redaction = Redaction {
+            emails: true,
+            passwords: true,
+        };
+        let exported = String::from_utf8(export(&store, redaction).await).unwrap();
+        let mut record: Value = serde_json::from_str(exported.trim_end()).unwrap();
+        assert_eq!(record["Money"], 250);
+        record["Money"] = 300.into();
+        let exported = format!("{record}\n");
+
+        let report = import(&store, exported.as_bytes(), true).await;
+        assert_eq!(report.updated, 1, "{report}");
+
+        let alice = store.find_by_name("ALICE").await.unwrap().unwrap();
+        assert_eq!(alice.money, 300);
+        assert!(alice.verify_password(PASSWORD).unwrap());
+        assert_eq!(alice.email.as_deref(), Some("alice@example.com"));
+        assert!(
+            alice.email_verified,
+            "verification state is kept with the email"
+        );
+        assert!(alice.two_factor.is_some_and(|t| t.enabled));
+    }
+}
@@ -0,0 +1,115 @@
+use clap::{Args, Parser, Subcommand};
+
+use std::path::PathBuf;
+
+use crate::{backup::MemberNumberConflict, models::account::AccountRole};
+
+#[derive(Parser)]
+#[command(version, about = "Bondage Club server")]
+pub struct Cli {
+    /// TOML config file, `APP_` environment variables override its values
+    #[arg(long, global = true, default_value = "bondage-club.toml")]
+    pub config: PathBuf,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Default)]
+pub enum Command {
+    /// Run the game server, the default
+    #[default]
+    Serve,
+    /// Upgrade every stored account to the current schema version
+    MigrateAccounts {
+        /// Only report what would change
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Copy the MongoDB accounts collection into the SQLite database
+    #[cfg(feature = "sqlite")]
+    MigrateMongoToSqlite,
+    /// Write every account as JSON Lines, for backups or to fill another database
+    Export {
+        /// Standard output when not set
+        #[arg(long)]
+        output: Option<PathBuf>,
+        #[arg(long)]
+        redact_emails: bool,
+        /// Leaves out password hashes and two-factor secrets
+        #[arg(long)]
+        redact_passwords: bool,
+    },
+    /// Load accounts from a JSON Lines export, records are checked like client data
+    Import {
+        /// Standard input when not set
+        #[arg(long)]
+        input: Option<PathBuf>,
+        /// Overwrite accounts with the same account name instead of skipping them
+        #[arg(long)]
+        upsert: bool,
+        /// What to do with an account whose member number belongs to another account
+        #[arg(long, value_enum, default_value_t)]
+        on_member_number_conflict: MemberNumberConflict,
+        /// Only report what would be imported
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Show an account
+    Lookup(AccountTarget),
+    /// Set a new password, asked for on the terminal or read from the first line of stdin
+    ResetPassword {
+        #[command(flatten)]
+        target: AccountTarget,
+        /// Read the password from the first line of this file instead
+        #[arg(long)]
+        password_file: Option<PathBuf>,
+    },
+    /// Set the money of the character
+    SetMoney {
+        #[command(flatten)]
+        target: AccountTarget,
+        money: u32,
+    },
+    /// Give a staff role
+    GrantRole {
+        #[command(flatten)]
+        target: AccountTarget,
+        role: AccountRole,
+    },
+    /// Take a staff role away
+    RevokeRole {
+        #[command(flatten)]
+        target: AccountTarget,
+        role: AccountRole,
+    },
+    /// Keep an account from logging in, takes effect at its next login
+    Ban {
+        #[command(flatten)]
+        target: AccountTarget,
+        #[arg(long)]
+        reason: String,
+        /// Ban for this many days instead of forever
+        #[arg(long)]
+        days: Option<u32>,
+    },
+    /// Lift a ban
+    Unban(AccountTarget),
+    /// Change the character name
+    Rename {
+        #[command(flatten)]
+        target: AccountTarget,
+        name: String,
+    },
+    /// Free the character from its owner
+    ReleaseOwnership(AccountTarget),
+}
+
+// The account an admin command works on, no doc comment as clap would show it for every command
+#[derive(Args)]
+#[group(required = true, multiple = false)]
+pub struct AccountTarget {
+    #[arg(long)]
+    pub account_name: Option<String>,
+    #[arg(long)]
+    pub member_number: Option<u32>,
+}
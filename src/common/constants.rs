@@ -1,11 +1,17 @@
 use regex::Regex;
 use std::sync::LazyLock;
 
-pub static SERVER_ACCOUNT_NAME_REGEX: LazyLock<Regex> =
-    LazyLock::new(|| Regex::new(r"^[a-zA-Z0-9]{1,20}$").unwrap());
-pub static SERVER_CHARACTER_NAME_REGEX: LazyLock<Regex> =
-    LazyLock::new(|| Regex::new(r"^[a-zA-Z0-9_]{1,32}$").unwrap());
-pub static SERVER_ACCOUNT_PASSWORD_REGEX: LazyLock<Regex> =
-    LazyLock::new(|| Regex::new(r"^[a-zA-Z0-9]{1,20}$").unwrap());
 pub static SERVER_ACCOUNT_EMAIL_REGEX: LazyLock<Regex> =
     LazyLock::new(|| Regex::new(r"^[a-zA-Z0-9@.!#$%&'*+/=?^_`{|}~-]{5,100}$").unwrap());
+
+pub const SERVER_ACCOUNT_DESCRIPTION_MAX_LENGTH: usize = 10_000;
+pub const SERVER_ACCOUNT_TITLE_MAX_LENGTH: usize = 100;
+pub const SERVER_ACCOUNT_NICKNAME_MAX_LENGTH: usize = 20;
+pub const SERVER_ACCOUNT_ITEM_PERMISSION_MAX: u8 = 5;
+pub const SERVER_ACCOUNT_MEMBER_LIST_MAX_SIZE: usize = 1_000;
+pub const SERVER_ACCOUNT_LOG_MAX_SIZE: usize = 2_000;
+pub const SERVER_ACCOUNT_APPEARANCE_MAX_SIZE: usize = 300;
+/// Reputations go from -100 to 100
+pub const SERVER_ACCOUNT_REPUTATION_MAX: i32 = 100;
+/// Upper bound on the serialized size of any free-form JSON field of an account
+pub const SERVER_ACCOUNT_BLOB_MAX_BYTES: usize = 180_000;
@@ -1,3 +1,4 @@
 pub mod constants;
 pub mod protocol;
 pub mod types;
+pub mod validation;
@@ -1,15 +1,178 @@
-use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::{Map, Value, json};
 use std::collections::HashSet;
 use utility_types::Partial;
 
-// #[derive(Debug, Clone, Deserialize)]
-// #[serde(tag = "event", content = "data")]
-// pub enum ClientToServerEvent {
-//     AccountCreate(AccountCreateRequest),
-//     AccountUpdate(AccountUpdateRequest),
-//     // Add more events here...
-// }
+use crate::{
+    common::validation::{FieldError, FieldErrorKind},
+    models::account::ServerFriendInfo,
+    models::character::{AppearanceItem, Game, Reputation, Skill},
+};
+
+/// Every event a client can send, with its payload. Variants are named after the events, a new
+/// one needs a line in `parse` and an arm in `BCServer::dispatch`.
+#[allow(clippy::enum_variant_names)]
+pub enum ClientToServerEvent {
+    AccountCreate(AccountCreateRequest),
+    AccountLogin(AccountLoginRequest),
+    AccountLoginTotp(TwoFactorCodeRequest),
+    AccountUpdate(Box<AccountUpdateRequest>),
+    AccountUpdateEmail(AccountUpdateEmailRequest),
+    AccountVerifyEmail(AccountVerifyEmailRequest),
+    AccountBeep(AccountBeepRequest),
+    AccountQuery(AccountQueryRequest),
+    AccountTwoFactorSetup,
+    AccountTwoFactorConfirm(TwoFactorCodeRequest),
+    AccountTwoFactorDisable(TwoFactorCodeRequest),
+}
+
+impl ClientToServerEvent {
+    /// Reads the payload of `event`, `None` when the server doesn't handle that event
+    pub fn parse(event: &str, data: Value) -> Option<Result<Self, InvalidPayload>> {
+        /// `is_write` tells if the event writes to the account and answers an ack callback
+        fn payload<T: DeserializeOwned>(
+            data: Value,
+            event: fn(T) -> ClientToServerEvent,
+            is_write: bool,
+        ) -> Option<Result<ClientToServerEvent, InvalidPayload>> {
+            Some(
+                serde_json::from_value(data)
+                    .map(event)
+                    .map_err(|error| InvalidPayload { error, is_write }),
+            )
+        }
+
+        match event {
+            "AccountCreate" => payload(data, Self::AccountCreate, false),
+            "AccountLogin" => payload(data, Self::AccountLogin, false),
+            "AccountLoginTotp" => payload(data, Self::AccountLoginTotp, false),
+            "AccountUpdate" => payload(data, |req| Self::AccountUpdate(Box::new(req)), true),
+            "AccountUpdateEmail" => payload(data, Self::AccountUpdateEmail, true),
+            "AccountVerifyEmail" => payload(data, Self::AccountVerifyEmail, true),
+            "AccountBeep" => payload(data, Self::AccountBeep, false),
+            "AccountQuery" => payload(data, Self::AccountQuery, false),
+            // Takes no payload, whatever comes along is ignored
+            "AccountTwoFactorSetup" => Some(Ok(Self::AccountTwoFactorSetup)),
+            "AccountTwoFactorConfirm" => payload(data, Self::AccountTwoFactorConfirm, true),
+            "AccountTwoFactorDisable" => payload(data, Self::AccountTwoFactorDisable, true),
+            _ => None,
+        }
+    }
+
+    /// What the client waiting on `event` receives when its payload can't be read, `None` for
+    /// events that get no answer
+    pub fn invalid_payload_response(event: &str) -> Option<(&'static str, Value)> {
+        match event {
+            "AccountCreate" => Some((
+                "CreationResponse",
+                json!(CreationResponse::InvalidRequestData),
+            )),
+            "AccountLogin" => Some(("LoginResponse", json!(LoginResponse::InvalidNamePassword))),
+            "AccountLoginTotp" => {
+                Some(("LoginResponse", json!(LoginResponse::InvalidTwoFactorCode)))
+            }
+            "AccountUpdate" => Some((
+                "AccountUpdateResponse",
+                json!({ "Result": "InvalidPayload" }),
+            )),
+            "AccountUpdateEmail" => Some((
+                "AccountQueryResult",
+                json!(AccountQueryResult::EmailUpdate(false)),
+            )),
+            "AccountVerifyEmail" => Some((
+                "AccountQueryResult",
+                json!(AccountQueryResult::EmailVerify(false)),
+            )),
+            "AccountTwoFactorConfirm" => Some((
+                "AccountTwoFactorResult",
+                json!({ "Action": "Confirm", "Result": false }),
+            )),
+            "AccountTwoFactorDisable" => Some((
+                "AccountTwoFactorResult",
+                json!({ "Action": "Disable", "Result": false }),
+            )),
+            // Beeps and queries have no failure answer, the client just doesn't get one
+            _ => None,
+        }
+    }
+}
+
+/// A payload that doesn't read as the request of its event
+#[derive(Debug)]
+pub struct InvalidPayload {
+    pub error: serde_json::Error,
+    /// Whether the event writes to the account and answers an ack callback
+    pub is_write: bool,
+}
+
+/// How far a write to the account got
+#[derive(Debug, PartialEq, Eq)]
+pub enum WriteOutcome {
+    Saved,
+    /// Only appearance, skill or game changed, they're kept in memory until the next delayed
+    /// update flush
+    Pending,
+}
+
+/// Why an event that writes to the account didn't go through
+#[derive(Debug)]
+pub enum WriteError {
+    InvalidPayload,
+    NotLoggedIn,
+    /// Some fields were dropped, the valid ones still went through as `accepted` tells
+    InvalidFields {
+        errors: Vec<FieldError>,
+        accepted: WriteOutcome,
+    },
+    /// The request itself was refused: wrong code, email in use...
+    Rejected,
+    StorageFailure,
+    /// Anything else that failed on the server side
+    ServerError,
+}
+
+/// Answer to the ack callback of an event that writes to the account
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct WriteAck {
+    pub success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<&'static str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+    /// With `InvalidFields`, the valid changes are kept in memory until the next flush
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub pending: bool,
+}
+
+impl From<Result<WriteOutcome, WriteError>> for WriteAck {
+    fn from(result: Result<WriteOutcome, WriteError>) -> Self {
+        let (code, errors, pending) = match result {
+            Ok(outcome) => {
+                return Self {
+                    success: true,
+                    code: (outcome == WriteOutcome::Pending).then_some("Pending"),
+                    errors: vec![],
+                    pending: false,
+                };
+            }
+            Err(WriteError::InvalidPayload) => ("InvalidPayload", vec![], false),
+            Err(WriteError::NotLoggedIn) => ("NotLoggedIn", vec![], false),
+            Err(WriteError::InvalidFields { errors, accepted }) => {
+                ("InvalidFields", errors, accepted == WriteOutcome::Pending)
+            }
+            Err(WriteError::Rejected) => ("Rejected", vec![], false),
+            Err(WriteError::StorageFailure) => ("StorageFailure", vec![], false),
+            Err(WriteError::ServerError) => ("ServerError", vec![], false),
+        };
+        Self {
+            success: false,
+            code: Some(code),
+            errors,
+            pending,
+        }
+    }
+}
 
 #[derive(Deserialize, Debug, Clone)]
 #[serde(rename_all = "PascalCase")]
@@ -29,7 +192,7 @@ pub struct AccountLoginRequest {
 
 #[derive(Deserialize, Serialize, Partial, Debug)]
 #[partial(ident = Account, derive(Debug, PartialEq), forward_attrs())]
-#[serde(rename_all = "PascalCase")]
+#[serde(rename_all = "PascalCase", from = "Map<String, Value>")]
 pub struct AccountUpdateRequest {
     pub name: Option<String>,
     pub item_permission: Option<u8>,
@@ -46,22 +209,88 @@ pub struct AccountUpdateRequest {
     pub inventory_data: Option<Value>,
     pub arousal_settings: Option<Value>,
     pub online_shared_settings: Option<Value>,
-    pub game: Option<Value>,
+    pub game: Option<Game>,
     pub map_data: Option<Value>,
     pub label_color: Option<Value>,
-    pub appearance: Option<Value>,
-    pub reputation: Option<Vec<String>>,
+    pub appearance: Option<Vec<AppearanceItem>>,
+    pub reputation: Option<Vec<Reputation>>,
     pub description: Option<String>,
     pub block_items: Option<Value>,
     pub limited_items: Option<Value>,
     pub favorite_items: Option<Value>,
     //pub lovership: Option<Vec<Value>>,
     //pub lover: Option<String>,
-    pub skill: Option<Value>,
+    pub skill: Option<Vec<Skill>>,
     pub title: Option<String>,
     pub nickname: Option<Value>,
     pub crafting: Option<Value>,
     pub log: Option<Vec<Value>>,
+    /// Fields sent with a value that doesn't read as their type, `validate` reports them
+    #[serde(skip)]
+    pub unreadable: Vec<FieldError>,
+}
+
+/// Takes the fields of an update one by one out of the payload
+struct FieldReader {
+    fields: Map<String, Value>,
+    unreadable: Vec<FieldError>,
+}
+
+impl FieldReader {
+    /// Null is the same as a missing field, anything else that isn't a `T` is unreadable
+    fn read<T: DeserializeOwned>(&mut self, field: &'static str) -> Option<T> {
+        let value = self.fields.remove(field).filter(|v| !v.is_null())?;
+        match serde_json::from_value(value) {
+            Ok(value) => Some(value),
+            Err(_) => {
+                self.unreadable.push(FieldError {
+                    field,
+                    error: FieldErrorKind::InvalidShape,
+                });
+                None
+            }
+        }
+    }
+}
+
+impl From<Map<String, Value>> for AccountUpdateRequest {
+    // One field the client got wrong doesn't lose the rest of the update
+    fn from(fields: Map<String, Value>) -> Self {
+        let mut reader = FieldReader {
+            fields,
+            unreadable: vec![],
+        };
+        Self {
+            name: reader.read("Name"),
+            item_permission: reader.read("ItemPermission"),
+            friend_list: reader.read("FriendList"),
+            white_list: reader.read("WhiteList"),
+            black_list: reader.read("BlackList"),
+            creation: reader.read("Creation"),
+            last_login: reader.read("LastLogin"),
+            chat_room: reader.read("ChatRoom"),
+            ownership: reader.read("Ownership"),
+            inventory_data: reader.read("InventoryData"),
+            arousal_settings: reader.read("ArousalSettings"),
+            online_shared_settings: reader.read("OnlineSharedSettings"),
+            game: reader.read("Game"),
+            map_data: reader.read("MapData"),
+            label_color: reader.read("LabelColor"),
+            appearance: reader.read("Appearance"),
+            reputation: reader.read("Reputation"),
+            description: reader.read("Description"),
+            block_items: reader.read("BlockItems"),
+            limited_items: reader.read("LimitedItems"),
+            favorite_items: reader.read("FavoriteItems"),
+            skill: reader.read("Skill"),
+            title: reader.read("Title"),
+            // Null clears the nickname, so it's kept as sent
+            nickname: reader.fields.remove("Nickname"),
+            crafting: reader.read("Crafting"),
+            log: reader.read("Log"),
+            unreadable: reader.unreadable,
+        }
+    }
 }
 
 #[derive(Deserialize)]
@@ -70,6 +299,25 @@ pub struct AccountQueryRequest {
     pub query: String,
 }
 
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct AccountUpdateEmailRequest {
+    pub email_old: String,
+    pub email_new: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct AccountVerifyEmailRequest {
+    pub token: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct TwoFactorCodeRequest {
+    pub code: String,
+}
+
 #[derive(Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct AccountBeepRequest {
@@ -80,3 +328,267 @@ pub struct AccountBeepRequest {
     pub beep_type: Option<String>,
     pub message: Option<Value>,
 }
+
+/// Answers to `AccountCreate`, the failures are shown as is by the client
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub enum CreationResponse {
+    #[serde(rename = "Invalid request data")]
+    InvalidRequestData,
+    #[serde(rename = "Invalid account name")]
+    InvalidAccountName,
+    #[serde(rename = "Invalid password")]
+    InvalidPassword,
+    #[serde(rename = "Invalid character name")]
+    InvalidCharacterName,
+    #[serde(rename = "Invalid email address")]
+    InvalidEmailAddress,
+    #[serde(rename = "New accounts per day exceeded")]
+    AccountsPerDayExceeded,
+    #[serde(rename = "Account already exists")]
+    AccountAlreadyExists,
+    #[serde(rename = "Email already in use")]
+    EmailAlreadyInUse,
+    #[serde(rename = "Server error")]
+    ServerError,
+    #[serde(rename = "Server is shutting down")]
+    ServerShuttingDown,
+    #[serde(untagged)]
+    AccountCreated(AccountCreated),
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+pub struct AccountCreated {
+    server_answer: &'static str,
+    #[serde(rename = "OnlineID")]
+    pub online_id: String,
+    pub member_number: u32,
+}
+
+impl CreationResponse {
+    pub fn account_created(online_id: String, member_number: u32) -> Self {
+        Self::AccountCreated(AccountCreated {
+            server_answer: "AccountCreated",
+            online_id,
+            member_number,
+        })
+    }
+}
+
+/// Failed answers to `AccountLogin` and `AccountLoginTotp`, a successful login gets the account
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoginResponse {
+    /// Also sent for malformed names and passwords, so the client shows its usual message
+    InvalidNamePassword,
+    AccountBanned,
+    TwoFactorRequired,
+    InvalidTwoFactorCode,
+    ServerError,
+    ServerShuttingDown,
+}
+
+/// Answers to `AccountQuery` and to the email events, which reuse `AccountQueryResult`
+#[derive(Serialize, Debug)]
+#[serde(tag = "Query", content = "Result")]
+pub enum AccountQueryResult {
+    OnlineFriends(Vec<ServerFriendInfo>),
+    EmailStatus(bool),
+    EmailVerified(bool),
+    EmailUpdate(bool),
+    EmailVerify(bool),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wire(value: impl Serialize) -> Value {
+        serde_json::to_value(value).unwrap()
+    }
+
+    #[test]
+    fn creation_response_strings() {
+        for (response, expected) in [
+            (CreationResponse::InvalidRequestData, "Invalid request data"),
+            (CreationResponse::InvalidAccountName, "Invalid account name"),
+            (CreationResponse::InvalidPassword, "Invalid password"),
+            (
+                CreationResponse::InvalidCharacterName,
+                "Invalid character name",
+            ),
+            (
+                CreationResponse::InvalidEmailAddress,
+                "Invalid email address",
+            ),
+            (
+                CreationResponse::AccountsPerDayExceeded,
+                "New accounts per day exceeded",
+            ),
+            (
+                CreationResponse::AccountAlreadyExists,
+                "Account already exists",
+            ),
+            (CreationResponse::EmailAlreadyInUse, "Email already in use"),
+            (CreationResponse::ServerError, "Server error"),
+            (
+                CreationResponse::ServerShuttingDown,
+                "Server is shutting down",
+            ),
+        ] {
+            assert_eq!(wire(response), json!(expected));
+        }
+    }
+
+    #[test]
+    fn account_created() {
+        assert_eq!(
+            wire(CreationResponse::account_created("ALICE".into(), 42)),
+            json!({ "ServerAnswer": "AccountCreated", "OnlineID": "ALICE", "MemberNumber": 42 })
+        );
+    }
+
+    #[test]
+    fn login_response_strings() {
+        for (response, expected) in [
+            (LoginResponse::InvalidNamePassword, "InvalidNamePassword"),
+            (LoginResponse::AccountBanned, "AccountBanned"),
+            (LoginResponse::TwoFactorRequired, "TwoFactorRequired"),
+            (LoginResponse::InvalidTwoFactorCode, "InvalidTwoFactorCode"),
+            (LoginResponse::ServerError, "ServerError"),
+            (LoginResponse::ServerShuttingDown, "ServerShuttingDown"),
+        ] {
+            assert_eq!(wire(response), json!(expected));
+        }
+    }
+
+    #[test]
+    fn account_query_results() {
+        assert_eq!(
+            wire(AccountQueryResult::OnlineFriends(vec![ServerFriendInfo {
+                r#type: "Friend",
+                member_number: 7,
+                member_name: "Bob".into(),
+            }])),
+            json!({
+                "Query": "OnlineFriends",
+                "Result": [{ "Type": "Friend", "MemberNumber": 7, "MemberName": "Bob" }]
+            })
+        );
+        assert_eq!(
+            wire(AccountQueryResult::EmailStatus(true)),
+            json!({ "Query": "EmailStatus", "Result": true })
+        );
+        assert_eq!(
+            wire(AccountQueryResult::EmailVerified(false)),
+            json!({ "Query": "EmailVerified", "Result": false })
+        );
+        assert_eq!(
+            wire(AccountQueryResult::EmailUpdate(false)),
+            json!({ "Query": "EmailUpdate", "Result": false })
+        );
+        assert_eq!(
+            wire(AccountQueryResult::EmailVerify(true)),
+            json!({ "Query": "EmailVerify", "Result": true })
+        );
+    }
+
+    #[test]
+    fn invalid_payload_responses() {
+        let response = |event| ClientToServerEvent::invalid_payload_response(event).unwrap();
+        assert_eq!(
+            response("AccountCreate"),
+            ("CreationResponse", json!("Invalid request data"))
+        );
+        assert_eq!(
+            response("AccountLogin"),
+            ("LoginResponse", json!("InvalidNamePassword"))
+        );
+        assert_eq!(
+            response("AccountLoginTotp"),
+            ("LoginResponse", json!("InvalidTwoFactorCode"))
+        );
+        assert_eq!(
+            response("AccountUpdateEmail"),
+            (
+                "AccountQueryResult",
+                json!({ "Query": "EmailUpdate", "Result": false })
+            )
+        );
+    }
+
+    fn title_too_long() -> FieldError {
+        FieldError {
+            field: "Title",
+            error: FieldErrorKind::TooLong,
+        }
+    }
+
+    #[test]
+    fn write_acks() {
+        assert_eq!(
+            wire(WriteAck::from(Ok(WriteOutcome::Saved))),
+            json!({ "Success": true })
+        );
+        assert_eq!(
+            wire(WriteAck::from(Ok(WriteOutcome::Pending))),
+            json!({ "Success": true, "Code": "Pending" })
+        );
+        assert_eq!(
+            wire(WriteAck::from(Err(WriteError::InvalidFields {
+                errors: vec![title_too_long()],
+                accepted: WriteOutcome::Saved,
+            }))),
+            json!({
+                "Success": false,
+                "Code": "InvalidFields",
+                "Errors": [{ "Field": "Title", "Error": "TooLong" }],
+            })
+        );
+        assert_eq!(
+            wire(WriteAck::from(Err(WriteError::InvalidFields {
+                errors: vec![title_too_long()],
+                accepted: WriteOutcome::Pending,
+            }))),
+            json!({
+                "Success": false,
+                "Code": "InvalidFields",
+                "Errors": [{ "Field": "Title", "Error": "TooLong" }],
+                "Pending": true,
+            })
+        );
+        for (error, code) in [
+            (WriteError::InvalidPayload, "InvalidPayload"),
+            (WriteError::NotLoggedIn, "NotLoggedIn"),
+            (WriteError::Rejected, "Rejected"),
+            (WriteError::StorageFailure, "StorageFailure"),
+            (WriteError::ServerError, "ServerError"),
+        ] {
+            assert_eq!(
+                wire(WriteAck::from(Err(error))),
+                json!({ "Success": false, "Code": code })
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_payloads_know_whether_they_were_writes() {
+        let parse = |event| ClientToServerEvent::parse(event, json!("garbage")).unwrap();
+        for event in [
+            "AccountUpdate",
+            "AccountUpdateEmail",
+            "AccountVerifyEmail",
+            "AccountTwoFactorConfirm",
+            "AccountTwoFactorDisable",
+        ] {
+            assert!(parse(event).is_err_and(|e| e.is_write), "{event}");
+        }
+        for event in [
+            "AccountCreate",
+            "AccountLogin",
+            "AccountBeep",
+            "AccountQuery",
+        ] {
+            assert!(parse(event).is_err_and(|e| !e.is_write), "{event}");
+        }
+    }
+}
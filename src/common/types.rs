@@ -1,5 +1,10 @@
 use socketioxide::extract::SocketRef;
-use std::{net::IpAddr, time::SystemTime};
+use std::{
+    net::IpAddr,
+    time::{Instant, SystemTime},
+};
+
+use crate::models::account::Account;
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct AccountCreationIP {
@@ -12,4 +17,20 @@ pub struct LoginQueueStruct {
     pub socket: SocketRef,
     pub account_name: String,
     pub password: String,
+    pub queued_at: Instant,
+}
+
+/// A login that passed the password check and waits for its TOTP code
+#[derive(Debug)]
+pub struct PendingTwoFactor {
+    pub account: Account,
+    pub attempts: u8,
+    pub expires: SystemTime,
+}
+
+/// Wrong two-factor codes of an account, counted over all its sockets
+#[derive(Debug, Default)]
+pub struct TwoFactorFailures {
+    pub count: u8,
+    pub since: Option<SystemTime>,
 }
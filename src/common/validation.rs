@@ -0,0 +1,360 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashSet;
+
+use crate::common::{
+    constants::{
+        SERVER_ACCOUNT_APPEARANCE_MAX_SIZE, SERVER_ACCOUNT_BLOB_MAX_BYTES,
+        SERVER_ACCOUNT_DESCRIPTION_MAX_LENGTH, SERVER_ACCOUNT_ITEM_PERMISSION_MAX,
+        SERVER_ACCOUNT_LOG_MAX_SIZE, SERVER_ACCOUNT_MEMBER_LIST_MAX_SIZE,
+        SERVER_ACCOUNT_NICKNAME_MAX_LENGTH, SERVER_ACCOUNT_REPUTATION_MAX,
+        SERVER_ACCOUNT_TITLE_MAX_LENGTH,
+    },
+    protocol::AccountUpdateRequest,
+};
+use crate::models::character::{AppearanceItem, Reputation};
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldErrorKind {
+    TooLong,
+    TooMany,
+    OutOfRange,
+    InvalidShape,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "PascalCase")]
+pub struct FieldError {
+    pub field: &'static str,
+    pub error: FieldErrorKind,
+}
+
+/// Drops the field from the request and records why, if `check` rejects it
+fn check_field<T>(
+    errors: &mut Vec<FieldError>,
+    field: &'static str,
+    value: &mut Option<T>,
+    check: impl FnOnce(&T) -> Result<(), FieldErrorKind>,
+) {
+    if let Some(inner) = value.as_ref()
+        && let Err(error) = check(inner)
+    {
+        errors.push(FieldError { field, error });
+        *value = None;
+    }
+}
+
+fn check_length(text: &str, max: usize) -> Result<(), FieldErrorKind> {
+    if text.chars().count() > max {
+        return Err(FieldErrorKind::TooLong);
+    }
+    Ok(())
+}
+
+fn check_member_list(list: &HashSet<u32>) -> Result<(), FieldErrorKind> {
+    if list.len() > SERVER_ACCOUNT_MEMBER_LIST_MAX_SIZE {
+        return Err(FieldErrorKind::TooMany);
+    }
+    Ok(())
+}
+
+fn check_blob(value: &impl Serialize) -> Result<(), FieldErrorKind> {
+    let size = serde_json::to_string(value).map(|s| s.len()).unwrap_or(0);
+    if size > SERVER_ACCOUNT_BLOB_MAX_BYTES {
+        return Err(FieldErrorKind::TooLong);
+    }
+    Ok(())
+}
+
+fn check_object(value: &Value) -> Result<(), FieldErrorKind> {
+    if !value.is_object() {
+        return Err(FieldErrorKind::InvalidShape);
+    }
+    check_blob(value)
+}
+
+fn has_string_keys(entry: &Value, keys: &[&str]) -> bool {
+    keys.iter()
+        .all(|key| entry.get(key).is_some_and(Value::is_string))
+}
+
+fn check_appearance(items: &[AppearanceItem]) -> Result<(), FieldErrorKind> {
+    if items.len() > SERVER_ACCOUNT_APPEARANCE_MAX_SIZE {
+        return Err(FieldErrorKind::TooMany);
+    }
+    check_blob(&items)
+}
+
+fn check_reputation(reputation: &[Reputation]) -> Result<(), FieldErrorKind> {
+    if reputation
+        .iter()
+        .any(|r| r.value.abs() > SERVER_ACCOUNT_REPUTATION_MAX)
+    {
+        return Err(FieldErrorKind::OutOfRange);
+    }
+    check_blob(&reputation)
+}
+
+fn check_log(log: &[Value]) -> Result<(), FieldErrorKind> {
+    if log.len() > SERVER_ACCOUNT_LOG_MAX_SIZE {
+        return Err(FieldErrorKind::TooMany);
+    }
+    if !log
+        .iter()
+        .all(|entry| has_string_keys(entry, &["Name", "Group"]))
+    {
+        return Err(FieldErrorKind::InvalidShape);
+    }
+    Ok(())
+}
+
+fn check_nickname(value: &Value) -> Result<(), FieldErrorKind> {
+    match value {
+        Value::Null => Ok(()),
+        Value::String(nickname) => check_length(nickname, SERVER_ACCOUNT_NICKNAME_MAX_LENGTH),
+        _ => Err(FieldErrorKind::InvalidShape),
+    }
+}
+
+impl AccountUpdateRequest {
+    /// Removes every invalid field from the request, so only sane data reaches the database.
+    /// Returns the list of rejected fields to report back to the client.
+    pub fn validate(&mut self) -> Vec<FieldError> {
+        let mut errors = std::mem::take(&mut self.unreadable);
+
+        check_field(
+            &mut errors,
+            "ItemPermission",
+            &mut self.item_permission,
+            |p| {
+                if *p > SERVER_ACCOUNT_ITEM_PERMISSION_MAX {
+                    return Err(FieldErrorKind::OutOfRange);
+                }
+                Ok(())
+            },
+        );
+        check_field(&mut errors, "Description", &mut self.description, |d| {
+            check_length(d, SERVER_ACCOUNT_DESCRIPTION_MAX_LENGTH)
+        });
+        check_field(&mut errors, "Title", &mut self.title, |t| {
+            check_length(t, SERVER_ACCOUNT_TITLE_MAX_LENGTH)
+        });
+        check_field(&mut errors, "Nickname", &mut self.nickname, check_nickname);
+        check_field(
+            &mut errors,
+            "FriendList",
+            &mut self.friend_list,
+            check_member_list,
+        );
+        check_field(
+            &mut errors,
+            "WhiteList",
+            &mut self.white_list,
+            check_member_list,
+        );
+        check_field(
+            &mut errors,
+            "BlackList",
+            &mut self.black_list,
+            check_member_list,
+        );
+        check_field(&mut errors, "Log", &mut self.log, |l| check_log(l));
+        check_field(&mut errors, "Appearance", &mut self.appearance, |a| {
+            check_appearance(a)
+        });
+        check_field(&mut errors, "Reputation", &mut self.reputation, |r| {
+            check_reputation(r)
+        });
+        check_field(
+            &mut errors,
+            "InventoryData",
+            &mut self.inventory_data,
+            check_blob,
+        );
+        check_field(&mut errors, "Crafting", &mut self.crafting, check_blob);
+        check_field(
+            &mut errors,
+            "ArousalSettings",
+            &mut self.arousal_settings,
+            check_object,
+        );
+        check_field(
+            &mut errors,
+            "OnlineSharedSettings",
+            &mut self.online_shared_settings,
+            check_object,
+        );
+        check_field(&mut errors, "Game", &mut self.game, check_blob);
+        check_field(&mut errors, "Skill", &mut self.skill, check_blob);
+        check_field(&mut errors, "MapData", &mut self.map_data, check_blob);
+        check_field(&mut errors, "LabelColor", &mut self.label_color, check_blob);
+        check_field(&mut errors, "BlockItems", &mut self.block_items, check_blob);
+        check_field(
+            &mut errors,
+            "LimitedItems",
+            &mut self.limited_items,
+            check_blob,
+        );
+        check_field(
+            &mut errors,
+            "FavoriteItems",
+            &mut self.favorite_items,
+            check_blob,
+        );
+
+        errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn validate(payload: Value) -> (AccountUpdateRequest, Vec<FieldError>) {
+        let mut request: AccountUpdateRequest = serde_json::from_value(payload).unwrap();
+        let errors = request.validate();
+        (request, errors)
+    }
+
+    fn rejected(field: &'static str, error: FieldErrorKind) -> FieldError {
+        FieldError { field, error }
+    }
+
+    #[test]
+    fn valid_fields_are_kept() {
+        let (request, errors) = validate(json!({
+            "ItemPermission": 3,
+            "Title": "Mistress",
+            "Nickname": "Kitty",
+            "FriendList": [1, 2],
+            "Log": [{ "Name": "Joined", "Group": "Club" }],
+            "ArousalSettings": { "Active": "Hybrid" },
+        }));
+        assert!(errors.is_empty());
+        assert_eq!(request.item_permission, Some(3));
+        assert_eq!(request.title.as_deref(), Some("Mistress"));
+        assert_eq!(request.nickname, Some(json!("Kitty")));
+        assert!(request.log.is_some());
+        assert!(request.arousal_settings.is_some());
+    }
+
+    #[test]
+    fn invalid_fields_are_dropped_and_reported() {
+        let (request, errors) = validate(json!({
+            "ItemPermission": 6,
+            "Title": "x".repeat(SERVER_ACCOUNT_TITLE_MAX_LENGTH + 1),
+            "Nickname": 5,
+            "Log": [{ "Name": "Joined" }],
+            "ArousalSettings": [],
+            "Description": "kept",
+        }));
+        assert_eq!(
+            errors,
+            vec![
+                rejected("ItemPermission", FieldErrorKind::OutOfRange),
+                rejected("Title", FieldErrorKind::TooLong),
+                rejected("Nickname", FieldErrorKind::InvalidShape),
+                rejected("Log", FieldErrorKind::InvalidShape),
+                rejected("ArousalSettings", FieldErrorKind::InvalidShape),
+            ]
+        );
+        assert!(request.item_permission.is_none());
+        assert!(request.title.is_none());
+        assert!(request.nickname.is_none());
+        assert!(request.log.is_none());
+        assert!(request.arousal_settings.is_none());
+        assert_eq!(request.description.as_deref(), Some("kept"));
+    }
+
+    #[test]
+    fn unreadable_fields_do_not_lose_the_rest() {
+        let (request, errors) = validate(json!({
+            "Appearance": [{ "Group": "Hat", "Name": "Beret" }, { "Name": "No group" }],
+            "Skill": [{ "Type": "Bondage", "Level": 2.5 }],
+            "ItemPermission": "high",
+            "Description": null,
+            "Title": "Mistress",
+        }));
+        assert_eq!(
+            errors,
+            vec![
+                rejected("ItemPermission", FieldErrorKind::InvalidShape),
+                rejected("Appearance", FieldErrorKind::InvalidShape),
+                rejected("Skill", FieldErrorKind::InvalidShape),
+            ]
+        );
+        assert!(request.appearance.is_none());
+        assert!(request.skill.is_none());
+        assert!(request.item_permission.is_none());
+        assert!(request.description.is_none(), "null leaves the field alone");
+        assert_eq!(request.title.as_deref(), Some("Mistress"));
+
+        // Only an object can be an update at all
+        assert!(serde_json::from_value::<AccountUpdateRequest>(json!([1])).is_err());
+    }
+
+    #[test]
+    fn null_nickname_is_kept() {
+        let (request, errors) = validate(json!({ "Nickname": null }));
+        assert!(errors.is_empty());
+        assert_eq!(request.nickname, Some(Value::Null));
+        let (request, _) = validate(json!({}));
+        assert_eq!(request.nickname, None);
+    }
+
+    #[test]
+    fn lengths_count_characters() {
+        let title = "é".repeat(SERVER_ACCOUNT_TITLE_MAX_LENGTH);
+        let (request, errors) = validate(json!({ "Title": title }));
+        assert!(errors.is_empty());
+        assert!(request.title.is_some());
+    }
+
+    #[test]
+    fn oversized_lists_and_blobs_are_rejected() {
+        let members: Vec<u32> = (0..=SERVER_ACCOUNT_MEMBER_LIST_MAX_SIZE as u32).collect();
+        let log = vec![json!({ "Name": "a", "Group": "b" }); SERVER_ACCOUNT_LOG_MAX_SIZE + 1];
+        let (_, errors) = validate(json!({
+            "WhiteList": members,
+            "Log": log,
+            "Crafting": "x".repeat(SERVER_ACCOUNT_BLOB_MAX_BYTES),
+        }));
+        assert_eq!(
+            errors,
+            vec![
+                rejected("WhiteList", FieldErrorKind::TooMany),
+                rejected("Log", FieldErrorKind::TooMany),
+                rejected("Crafting", FieldErrorKind::TooLong),
+            ]
+        );
+    }
+
+    #[test]
+    fn reputation_is_bounded() {
+        let max = SERVER_ACCOUNT_REPUTATION_MAX;
+        let (_, errors) = validate(json!({
+            "Reputation": [{ "Type": "Dominant", "Value": -max }, { "Type": "Kidnap", "Value": max }],
+        }));
+        assert!(errors.is_empty());
+        let (_, errors) = validate(json!({
+            "Reputation": [{ "Type": "Dominant", "Value": max + 1 }],
+        }));
+        assert_eq!(
+            errors,
+            vec![rejected("Reputation", FieldErrorKind::OutOfRange)]
+        );
+    }
+
+    #[test]
+    fn appearance_item_count_is_bounded() {
+        let item = json!({ "Group": "Hat", "Name": "Beret" });
+        let (_, errors) = validate(json!({
+            "Appearance": vec![item; SERVER_ACCOUNT_APPEARANCE_MAX_SIZE + 1],
+        }));
+        assert_eq!(
+            errors,
+            vec![rejected("Appearance", FieldErrorKind::TooMany)]
+        );
+    }
+}
@@ -1,3 +1,443 @@
+use axum::http::HeaderValue;
 use dotenvy::dotenv;
-use figment::{Figment, providers::Env};
-use serde::Deserialize;
+use figment::{
+    Figment,
+    providers::{Env, Format, Toml},
+};
+use ipnet::IpNet;
+use regex::Regex;
+use serde::{Deserialize, Deserializer, de};
+use std::{fmt, net::SocketAddr, path::Path};
+use tracing_subscriber::EnvFilter;
+
+use crate::{
+    common::constants::SERVER_ACCOUNT_ITEM_PERMISSION_MAX,
+    mailer::check_smtp_settings,
+    storage::StorageBackend,
+    utilities::client_ip::{ClientIpHeader, ClientIpResolver},
+};
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, with the fields of every enclosing span
+    Json,
+}
+
+/// Read from the TOML file given with `--config`, `APP_` environment variables override it
+#[derive(Debug, Deserialize)]
+pub struct AppConfig {
+    /// IP address and port the server listens on
+    pub server_addr: String,
+    #[serde(default)]
+    pub storage: StorageBackend,
+    #[serde(default = "default_db_uri")]
+    pub db_uri: String,
+    #[serde(default = "default_db_name")]
+    pub db_name: String,
+    #[serde(default = "default_db_accounts")]
+    pub db_accounts: String,
+    /// Collection with the counters shared by every server, like the last member number
+    #[serde(default = "default_db_counters")]
+    pub db_counters: String,
+    /// Upgrade every stored account to the current schema before accepting players,
+    /// otherwise accounts are only upgraded in memory when they are loaded
+    #[serde(default)]
+    pub migrate_accounts_on_startup: bool,
+    /// Database file used by the SQLite backend
+    #[serde(default = "default_sqlite_path")]
+    pub sqlite_path: String,
+    pub max_ip_account_per_day: u32,
+    pub max_ip_account_per_hour: u32,
+    #[serde(
+        default = "default_account_name_regex",
+        deserialize_with = "deserialize_regex"
+    )]
+    pub account_name_regex: Regex,
+    #[serde(
+        default = "default_account_password_regex",
+        deserialize_with = "deserialize_regex"
+    )]
+    pub account_password_regex: Regex,
+    #[serde(
+        default = "default_character_name_regex",
+        deserialize_with = "deserialize_regex"
+    )]
+    pub character_name_regex: Regex,
+    /// Work factor of password, recovery code and email token hashes, existing hashes keep theirs
+    #[serde(default = "default_bcrypt_cost")]
+    pub bcrypt_cost: u32,
+    #[serde(default = "default_starting_money")]
+    pub starting_money: u32,
+    #[serde(default = "default_starting_item_permission")]
+    pub starting_item_permission: u8,
+    /// Players are told their place in the login queue once it's longer than this
+    #[serde(default = "default_login_queue_notify_threshold")]
+    pub login_queue_notify_threshold: usize,
+    /// Pause between two queued logins, spreads out the password checks
+    #[serde(default = "default_login_delay_ms")]
+    pub login_delay_ms: u64,
+    /// Largest socket.io message accepted, in bytes
+    #[serde(default = "default_socket_max_buffer_size")]
+    pub socket_max_buffer_size: usize,
+    #[serde(default = "default_socket_ping_interval_secs")]
+    pub socket_ping_interval_secs: u64,
+    /// Clients that don't answer a ping within this are disconnected
+    #[serde(default = "default_socket_ping_timeout_secs")]
+    pub socket_ping_timeout_secs: u64,
+    /// Time a client has to join the namespace after opening the connection
+    #[serde(default = "default_socket_connect_timeout_secs")]
+    pub socket_connect_timeout_secs: u64,
+    /// SMTP server used for verification mails, mails are only logged when unset
+    pub smtp_url: Option<String>,
+    #[serde(default = "default_mail_from")]
+    pub mail_from: String,
+    /// Link sent in verification mails, `{token}` is replaced by the verification token
+    pub email_verification_url: Option<String>,
+    #[serde(default = "default_email_verification_ttl_hours")]
+    pub email_verification_ttl_hours: u64,
+    /// Serve HTTPS/WSS directly when both are set
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
+    /// How often the certificate files are checked for renewals
+    #[serde(default = "default_tls_reload_interval_secs")]
+    pub tls_reload_interval_secs: u64,
+    /// Plain HTTP listener redirecting to HTTPS
+    pub http_redirect_addr: Option<String>,
+    /// Origins allowed to connect, any origin is allowed when empty
+    #[serde(default)]
+    pub cors_allowed_origins: Vec<String>,
+    /// Header with the client address, only read from requests sent by a trusted proxy
+    #[serde(default)]
+    pub client_ip_header: ClientIpHeader,
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpNet>,
+    /// Origins of the production client, everything else is a development client
+    #[serde(default)]
+    pub prod_origins: Vec<String>,
+    /// Origins of known development clients, any non-production origin is accepted when empty
+    #[serde(default)]
+    pub dev_origins: Vec<String>,
+    /// Name shown in authenticator apps
+    #[serde(default = "default_totp_issuer")]
+    pub totp_issuer: String,
+    /// How often appearance, skill and game changes kept in memory are written to the database
+    #[serde(default = "default_delayed_update_flush_interval_secs")]
+    pub delayed_update_flush_interval_secs: u64,
+    /// How long a shutdown may take before the process exits anyway
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64,
+    /// Sent to every player when the server shuts down
+    #[serde(default = "default_shutdown_message")]
+    pub shutdown_message: String,
+    #[serde(default)]
+    pub log_format: LogFormat,
+    /// Which logs to keep, in the `RUST_LOG` syntax, e.g. `info,bondage_club_server_rs=debug`
+    #[serde(default = "default_log_filter")]
+    pub log_filter: String,
+    /// Serves Prometheus metrics on `/metrics`. Off unless asked for, they'd be public on the
+    /// game port otherwise.
+    #[serde(default)]
+    pub metrics_enabled: bool,
+    /// Serves the metrics on this address instead of the game port, to keep them private
+    pub metrics_addr: Option<String>,
+    /// How often every client gets the server info, 0 to only send it when players come and go
+    #[serde(default = "default_server_info_interval_secs")]
+    pub server_info_interval_secs: u64,
+}
+
+fn default_db_uri() -> String {
+    "mongodb://localhost:27017".to_string()
+}
+
+fn default_db_name() -> String {
+    "BondageClubDatabase".to_string()
+}
+
+fn default_db_accounts() -> String {
+    "Accounts".to_string()
+}
+
+fn default_db_counters() -> String {
+    "Counters".to_string()
+}
+
+fn default_sqlite_path() -> String {
+    "bondage-club.sqlite3".to_string()
+}
+
+fn default_account_name_regex() -> Regex {
+    Regex::new(r"^[a-zA-Z0-9]{1,20}$").unwrap()
+}
+
+fn default_account_password_regex() -> Regex {
+    Regex::new(r"^[a-zA-Z0-9]{1,20}$").unwrap()
+}
+
+fn default_character_name_regex() -> Regex {
+    Regex::new(r"^[a-zA-Z0-9_]{1,32}$").unwrap()
+}
+
+fn default_bcrypt_cost() -> u32 {
+    10
+}
+
+fn default_starting_money() -> u32 {
+    100
+}
+
+fn default_starting_item_permission() -> u8 {
+    2
+}
+
+fn default_login_queue_notify_threshold() -> usize {
+    16
+}
+
+fn default_login_delay_ms() -> u64 {
+    50
+}
+
+fn default_socket_max_buffer_size() -> usize {
+    180_000
+}
+
+fn default_socket_ping_interval_secs() -> u64 {
+    50
+}
+
+fn default_socket_ping_timeout_secs() -> u64 {
+    30
+}
+
+fn default_socket_connect_timeout_secs() -> u64 {
+    5
+}
+
+fn default_tls_reload_interval_secs() -> u64 {
+    60
+}
+
+fn default_mail_from() -> String {
+    "Bondage Club <noreply@localhost>".to_string()
+}
+
+fn default_email_verification_ttl_hours() -> u64 {
+    48
+}
+
+fn default_totp_issuer() -> String {
+    "Bondage Club".to_string()
+}
+
+fn default_delayed_update_flush_interval_secs() -> u64 {
+    60
+}
+
+fn default_shutdown_timeout_secs() -> u64 {
+    10
+}
+
+fn default_server_info_interval_secs() -> u64 {
+    30
+}
+
+fn default_log_filter() -> String {
+    "info".to_string()
+}
+
+fn default_shutdown_message() -> String {
+    "The server is restarting, please log in again in a few minutes".to_string()
+}
+
+fn deserialize_regex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Regex, D::Error> {
+    let pattern = String::deserialize(deserializer)?;
+    Regex::new(&pattern).map_err(de::Error::custom)
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// A value is missing or has the wrong type
+    Load(Box<figment::Error>),
+    /// Every value could be read, but they can't work together
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Load(e) => write!(f, "{e}"),
+            ConfigError::Invalid(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl AppConfig {
+    pub fn client_ip_resolver(&self) -> ClientIpResolver {
+        ClientIpResolver::new(self.client_ip_header, self.trusted_proxies.clone())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |message: &str| Err(ConfigError::Invalid(message.to_string()));
+        if self.server_addr.parse::<SocketAddr>().is_err() {
+            return Err(ConfigError::Invalid(format!(
+                "server_addr {:?} is not an IP address and port",
+                self.server_addr
+            )));
+        }
+        if !(4..=31).contains(&self.bcrypt_cost) {
+            return invalid("bcrypt_cost must be between 4 and 31");
+        }
+        if self.starting_item_permission > SERVER_ACCOUNT_ITEM_PERMISSION_MAX {
+            return invalid("starting_item_permission is out of range");
+        }
+        if self.socket_max_buffer_size == 0 {
+            return invalid("socket_max_buffer_size must not be 0");
+        }
+        if self.socket_connect_timeout_secs == 0 {
+            return invalid("socket_connect_timeout_secs must not be 0");
+        }
+        if self.socket_ping_interval_secs == 0 || self.socket_ping_timeout_secs == 0 {
+            return invalid("socket_ping_interval_secs and socket_ping_timeout_secs must not be 0");
+        }
+        if self.tls_reload_interval_secs == 0 {
+            return invalid("tls_reload_interval_secs must not be 0");
+        }
+        if self.delayed_update_flush_interval_secs == 0 {
+            return invalid("delayed_update_flush_interval_secs must not be 0");
+        }
+        if self.tls_cert_path.is_some() != self.tls_key_path.is_some() {
+            return invalid("tls_cert_path and tls_key_path must be set together");
+        }
+        if let Some(origin) = self
+            .cors_allowed_origins
+            .iter()
+            .find(|origin| HeaderValue::from_str(origin).is_err())
+        {
+            return Err(ConfigError::Invalid(format!(
+                "cors_allowed_origins: {origin:?} is not a valid header value"
+            )));
+        }
+        if let Some(url) = &self.smtp_url
+            && let Err(e) = check_smtp_settings(url, &self.mail_from)
+        {
+            return Err(ConfigError::Invalid(e));
+        }
+        if let Err(e) = EnvFilter::try_new(&self.log_filter) {
+            return Err(ConfigError::Invalid(format!("invalid log_filter: {e}")));
+        }
+        Ok(())
+    }
+}
+
+/// Defaults with the required settings filled in, for tests that need a whole server
+#[cfg(test)]
+pub fn test_config() -> AppConfig {
+    extract(Figment::new().merge(Toml::string(
+        r#"
+            server_addr = "127.0.0.1:0"
+            max_ip_account_per_day = 10
+            max_ip_account_per_hour = 5
+            bcrypt_cost = 4
+        "#,
+    )))
+    .unwrap()
+}
+
+/// Loads the config once at startup, a missing file is fine when everything comes from the
+/// environment
+pub fn load_config(path: &Path) -> Result<AppConfig, ConfigError> {
+    dotenv().ok();
+
+    extract(
+        Figment::new()
+            .merge(Toml::file(path))
+            .merge(Env::prefixed("APP_")),
+    )
+}
+
+fn extract(figment: Figment) -> Result<AppConfig, ConfigError> {
+    let config: AppConfig = figment
+        .extract()
+        .map_err(|e| ConfigError::Load(Box::new(e)))?;
+    config.validate()?;
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REQUIRED: &str = r#"
+        server_addr = "127.0.0.1:4288"
+        max_ip_account_per_day = 10
+        max_ip_account_per_hour = 5
+    "#;
+
+    fn from_toml(extra: &str) -> Result<AppConfig, ConfigError> {
+        extract(Figment::new().merge(Toml::string(&format!("{REQUIRED}{extra}"))))
+    }
+
+    #[test]
+    fn defaults_fill_missing_values() {
+        let config = from_toml("").unwrap();
+        assert_eq!(config.bcrypt_cost, 10);
+        assert_eq!(config.starting_money, 100);
+        assert_eq!(config.starting_item_permission, 2);
+        assert_eq!(config.login_queue_notify_threshold, 16);
+        assert_eq!(config.socket_max_buffer_size, 180_000);
+        assert!(!config.metrics_enabled);
+        assert!(config.account_name_regex.is_match("Alice"));
+        assert!(!config.account_name_regex.is_match("Alice!"));
+    }
+
+    #[test]
+    fn later_sources_override_earlier_ones() {
+        let figment = Figment::new()
+            .merge(Toml::string(&format!("{REQUIRED}starting_money = 50")))
+            .merge(Toml::string("starting_money = 75"));
+        assert_eq!(extract(figment).unwrap().starting_money, 75);
+    }
+
+    #[test]
+    fn invalid_regex_is_rejected() {
+        let result = from_toml(r#"account_name_regex = "^[a-z""#);
+        assert!(matches!(result, Err(ConfigError::Load(_))));
+    }
+
+    #[test]
+    fn out_of_range_values_are_rejected() {
+        for extra in [
+            "bcrypt_cost = 3",
+            "starting_item_permission = 6",
+            "socket_ping_interval_secs = 0",
+            "tls_reload_interval_secs = 0",
+            "delayed_update_flush_interval_secs = 0",
+            r#"tls_cert_path = "cert.pem""#,
+            "socket_connect_timeout_secs = 0",
+            r#"cors_allowed_origins = ["https://example.com", "bad\u0000origin"]"#,
+            r#"smtp_url = "ftp://smtp.example.com""#,
+            r#"smtp_url = "smtps://smtp.example.com"
+               mail_from = "not a mailbox""#,
+        ] {
+            let result = from_toml(extra);
+            assert!(matches!(result, Err(ConfigError::Invalid(_))), "{extra}");
+        }
+    }
+
+    #[test]
+    fn server_addr_must_be_an_ip_and_port() {
+        for server_addr in ["localhost", "0.0.0.0", "0.0.0.0:port"] {
+            let toml = REQUIRED.replace("127.0.0.1:4288", server_addr);
+            let result = extract(Figment::new().merge(Toml::string(&toml)));
+            assert!(
+                matches!(result, Err(ConfigError::Invalid(_))),
+                "{server_addr}"
+            );
+        }
+    }
+}
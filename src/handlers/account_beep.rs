@@ -23,9 +23,8 @@ impl BCServer {
             }
             let target = target.unwrap();
 
-            if
-            /* target.environment != account.unwrap().environment  ||*/
-            target.friend_list.is_empty()
+            if target.environment != account.environment
+                || target.friend_list.is_empty()
                 || !target.friend_list.contains(&account.member_number)
                 || target.ownership.is_none()
                 || (request.beep_type.is_some() && request.beep_type.clone().unwrap() != "Leash")
@@ -33,10 +32,10 @@ impl BCServer {
                 return;
             }
 
-            if let Some(ownership) = target.ownership.as_ref() {
-                if ownership.member_number != account.member_number {
-                    return;
-                }
+            if let Some(ownership) = target.ownership.as_ref()
+                && ownership.member_number != account.member_number
+            {
+                return;
             }
 
             let _ = target.socket.as_ref().unwrap().emit(
@@ -1,84 +1,101 @@
 use crate::{
     common::{
-        constants::{
-            SERVER_ACCOUNT_NAME_REGEX, SERVER_ACCOUNT_PASSWORD_REGEX, SERVER_CHARACTER_NAME_REGEX,
-        },
-        protocol::AccountCreateRequest,
+        protocol::{AccountCreateRequest, CreationResponse},
         types::AccountCreationIP,
     },
-    models::account::Account,
+    models::{account::Account, account_migrations::CURRENT_SCHEMA_VERSION},
+    monitoring::{ACCOUNT_CREATIONS, RATE_LIMIT_REJECTIONS},
     server::BCServer,
+    storage::StoreError,
     utilities::millis_timestamps::SystemTimeMillisTimestamps,
 };
-use axum::extract::ConnectInfo;
-use mongodb::bson::doc;
-use serde_json::json;
-use socketioxide::extract::{HttpExtension, SocketRef};
+use metrics::counter;
+use socketioxide::extract::SocketRef;
 use std::{
     collections::HashSet,
-    net::SocketAddr,
+    net::IpAddr,
     time::{Duration, SystemTime},
 };
+use tracing::{error, info};
+
+/// Member numbers tried before giving up on an account creation
+const MAX_MEMBER_NUMBER_ATTEMPTS: u32 = 5;
 
 impl BCServer {
-    pub async fn on_account_create(
-        &self,
-        socket: SocketRef,
-        request: AccountCreateRequest,
-        client_ip: HttpExtension<ConnectInfo<SocketAddr>>,
-    ) {
-        if !SERVER_ACCOUNT_NAME_REGEX.is_match(&request.account_name) {
-            println!(
-                "AccountCreate: Invalid AccountName: {}",
-                request.account_name
-            );
-            let _ = socket.emit("CreationResponse", "Invalid account name");
+    pub async fn on_account_create(&self, socket: SocketRef, request: AccountCreateRequest) {
+        if self.is_shutting_down() {
+            let _ = socket.emit("CreationResponse", &CreationResponse::ServerShuttingDown);
             return;
         }
 
-        if !SERVER_ACCOUNT_PASSWORD_REGEX.is_match(&request.password) {
-            println!("AccountCreate: Invalid Password");
-            let _ = socket.emit("CreationResponse", "Invalid password");
+        if !self
+            .config
+            .account_name_regex
+            .is_match(&request.account_name)
+        {
+            info!(account_name = %request.account_name, "invalid account name");
+            let _ = socket.emit("CreationResponse", &CreationResponse::InvalidAccountName);
             return;
         }
 
-        if !SERVER_CHARACTER_NAME_REGEX.is_match(&request.name) {
-            println!("AccountCreate: Invalid Name: {}", request.name);
-            let _ = socket.emit("CreationResponse", "Invalid character name");
+        if !self
+            .config
+            .account_password_regex
+            .is_match(&request.password)
+        {
+            info!("invalid password");
+            let _ = socket.emit("CreationResponse", &CreationResponse::InvalidPassword);
             return;
         }
 
-        if let Some(email) = &request.email {
-            if !email.is_empty() && !Account::is_valid_mail(email) {
-                println!("AccountCreate: Invalid Email: {:?}", request.email);
-                let _ = socket.emit("CreationResponse", "Invalid email address");
-                return;
-            }
+        if !self.config.character_name_regex.is_match(&request.name) {
+            info!(name = %request.name, "invalid character name");
+            let _ = socket.emit("CreationResponse", &CreationResponse::InvalidCharacterName);
+            return;
+        }
+
+        if let Some(email) = &request.email
+            && !email.is_empty()
+            && !Account::is_valid_mail(email)
+        {
+            info!("invalid email");
+            let _ = socket.emit("CreationResponse", &CreationResponse::InvalidEmailAddress);
+            return;
         }
 
         // FIXME: this looks silly
         let account_name = request.account_name;
         let name = request.name;
         let password = request.password;
-        let email = request.email;
+        let email = request
+            .email
+            .map(|e| Account::normalize_mail(&e))
+            .filter(|e| !e.is_empty());
 
+        let Some(client_ip) = self.client_ip(&socket) else {
+            // The rate limits can't work without an address, better refuse than skip them
+            error!("no peer address on the socket, refusing the account creation");
+            let _ = socket.emit("CreationResponse", &CreationResponse::ServerError);
+            return;
+        };
         if !self.check_creation_ratelimits(client_ip).await {
-            let _ = socket.emit("CreationResponse", "New accounts per day exceeded");
+            counter!(RATE_LIMIT_REJECTIONS, "limit" => "account_creation").increment(1);
+            let _ = socket.emit(
+                "CreationResponse",
+                &CreationResponse::AccountsPerDayExceeded,
+            );
             return;
         }
 
-        let users: mongodb::Collection<Account> = self.db.collection(&self.config.db_accounts);
-        let account: Result<Option<Account>, mongodb::error::Error> = users
-            .find_one(doc! { "AccountName": account_name.to_uppercase() }, None)
-            .await;
+        let account = self.store.find_by_name(&account_name.to_uppercase()).await;
         match account {
             Ok(Some(_)) => {
-                let _ = socket.emit("CreationResponse", "Account already exists");
+                let _ = socket.emit("CreationResponse", &CreationResponse::AccountAlreadyExists);
                 return;
             }
             Err(err) => {
-                println!("MongoDB error while checking existing account: {err}");
-                let _ = socket.emit("CreationResponse", "Server error");
+                error!("storage error while checking existing account: {err}");
+                let _ = socket.emit("CreationResponse", &CreationResponse::ServerError);
                 return;
             }
             Ok(None) => {
@@ -86,44 +103,107 @@ impl BCServer {
             }
         }
 
+        if let Some(email) = &email {
+            match self.is_email_in_use(email).await {
+                Ok(false) => {}
+                Ok(true) => {
+                    let _ = socket.emit("CreationResponse", &CreationResponse::EmailAlreadyInUse);
+                    return;
+                }
+                Err(err) => {
+                    error!("storage error while checking existing email: {err}");
+                    let _ = socket.emit("CreationResponse", &CreationResponse::ServerError);
+                    return;
+                }
+            }
+        }
+        let verification = match &email {
+            Some(_) => match self.new_email_verification().await {
+                Some(v) => Some(v),
+                None => {
+                    let _ = socket.emit("CreationResponse", &CreationResponse::ServerError);
+                    return;
+                }
+            },
+            None => None,
+        };
+
         // Create a hashed password and saves it with the account info
-        let hash = match bcrypt::hash(password.to_uppercase(), 10) {
+        let hash = match Account::hash_password(&password, self.config.bcrypt_cost) {
             Ok(h) => h,
             Err(e) => {
-                println!("Password hashing failed: {e}");
-                let _ = socket.emit("CreationResponse", "Server error");
+                error!("password hashing failed: {e}");
+                let _ = socket.emit("CreationResponse", &CreationResponse::ServerError);
                 return;
             }
         };
-        let mut account: Account;
-        {
-            let mut next_member_number = self.next_member_number.write().await;
-            account = Account {
-                account_name: account_name.to_uppercase(),
-                name,
-                password: Some(hash),
-                email: Some(email.unwrap().to_string()),
-                member_number: *next_member_number,
-                //Lovership: [],
-                item_permission: 2,
-                friend_list: HashSet::new(),
-                white_list: HashSet::new(),
-                black_list: HashSet::new(),
-                money: 100,
-                creation: SystemTime::now().get_timestamp_in_milliseconds(),
-                last_login: SystemTime::now().get_timestamp_in_milliseconds(),
-                environment: "PROD".to_string(), //account_get_environment(socket),
-                ..Default::default()
+        let mut account = Account {
+            account_name: account_name.to_uppercase(),
+            schema_version: CURRENT_SCHEMA_VERSION,
+            name,
+            password: Some(hash),
+            email: email.clone(),
+            email_verification: verification.as_ref().map(|(_, v)| v.clone()),
+            //Lovership: [],
+            item_permission: self.config.starting_item_permission,
+            friend_list: HashSet::new(),
+            white_list: HashSet::new(),
+            black_list: HashSet::new(),
+            money: self.config.starting_money,
+            creation: SystemTime::now().get_timestamp_in_milliseconds(),
+            last_login: SystemTime::now().get_timestamp_in_milliseconds(),
+            environment: self.account_get_environment(&socket),
+            ..Default::default()
+        };
+
+        let mut attempts = 0;
+        loop {
+            account.member_number = match self.store.next_member_number().await {
+                Ok(member_number) => member_number,
+                Err(e) => {
+                    error!("member number allocation failed: {e}");
+                    let _ = socket.emit("CreationResponse", &CreationResponse::ServerError);
+                    return;
+                }
             };
-            match users.insert_one(&account, None).await {
-                Ok(_) => {
-                    *next_member_number += 1;
+            match self.store.insert(&account).await {
+                Ok(()) => {
+                    counter!(ACCOUNT_CREATIONS).increment(1);
+                    info!(
+                        account_name = %account.account_name,
+                        member_number = account.member_number,
+                        "account created"
+                    );
+                    break;
+                }
+                Err(StoreError::Duplicate(e)) => {
+                    // Someone else registered the same name or email meanwhile
+                    if let Ok(Some(_)) = self.store.find_by_name(&account.account_name).await {
+                        let _ = socket
+                            .emit("CreationResponse", &CreationResponse::AccountAlreadyExists);
+                        return;
+                    }
+                    if let Some(email) = &email
+                        && let Ok(true) = self.is_email_in_use(email).await
+                    {
+                        let _ =
+                            socket.emit("CreationResponse", &CreationResponse::EmailAlreadyInUse);
+                        return;
+                    }
+                    // Otherwise the member number was taken, by an account the counter didn't know about
+                    attempts += 1;
+                    if attempts >= MAX_MEMBER_NUMBER_ATTEMPTS {
+                        error!("account insertion failed: {e}");
+                        let _ = socket.emit("CreationResponse", &CreationResponse::ServerError);
+                        return;
+                    }
                 }
                 Err(e) => {
-                    println!("Account insertion failed: {e}");
-                    let _ = socket.emit("CreationResponse", "Server error");
+                    error!("account insertion failed: {e}");
+                    let _ = socket.emit("CreationResponse", &CreationResponse::ServerError);
+                    return;
                 }
-            };
+            }
         }
         account.id = Some(socket.id.to_string());
         account.socket = Some(socket.clone());
@@ -133,21 +213,19 @@ impl BCServer {
 
         let _ = socket.emit(
             "CreationResponse",
-            &json!({
-                "ServerAnswer": "AccountCreated",
-                "OnlineID": account.account_name.to_uppercase(),
-                "MemberNumber": account.member_number,
-            }),
+            &CreationResponse::account_created(
+                account.account_name.to_uppercase(),
+                account.member_number,
+            ),
         );
         self.emit_server_info(socket).await;
+        if let (Some(email), Some((token, _))) = (&email, &verification) {
+            self.send_verification_mail(email, token).await;
+        }
         //AccountPurgeInfo(data);
     }
 
-    async fn check_creation_ratelimits(
-        &self,
-        client_ip: HttpExtension<ConnectInfo<SocketAddr>>,
-    ) -> bool {
-        let current_ip = client_ip.ip().to_canonical();
+    async fn check_creation_ratelimits(&self, current_ip: IpAddr) -> bool {
         let current_time = SystemTime::now();
         let mut total_count: u32 = 0;
         let mut hour_count: u32 = 0;
@@ -0,0 +1,113 @@
+use socketioxide::socket::Sid;
+use std::{sync::Arc, time::Duration};
+use tracing::{error, info};
+
+use crate::{server::BCServer, storage::AccountPatch};
+
+/// How much appearance, skill and game data only lives in memory right now
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DelayedUpdateStats {
+    pub accounts: usize,
+    pub bytes: usize,
+}
+
+impl BCServer {
+    pub async fn delayed_update_stats(&self) -> DelayedUpdateStats {
+        let accounts = self.accounts.lock().await;
+        accounts
+            .iter()
+            .map(|a| a.delayed_updates_size())
+            .filter(|size| *size > 0)
+            .fold(DelayedUpdateStats::default(), |stats, size| {
+                DelayedUpdateStats {
+                    accounts: stats.accounts + 1,
+                    bytes: stats.bytes + size,
+                }
+            })
+    }
+
+    /// Writes every pending delayed update to the database
+    pub async fn flush_delayed_updates(&self) {
+        let stats = self.delayed_update_stats().await;
+        if stats.accounts == 0 {
+            return;
+        }
+        info!(
+            accounts = stats.accounts,
+            bytes = stats.bytes,
+            "flushing delayed updates"
+        );
+
+        let pending: Vec<String> = {
+            let accounts = self.accounts.lock().await;
+            accounts
+                .iter()
+                .filter(|a| a.delayed_updates_size() > 0)
+                .map(|a| a.account_name.clone())
+                .collect()
+        };
+        for account_name in pending {
+            let _write = self.account_writes.lock(&account_name).await;
+            // Taken only now, an update saved meanwhile already wrote them
+            let patch = {
+                let mut accounts = self.accounts.lock().await;
+                accounts
+                    .iter_mut()
+                    .find(|a| a.account_name == account_name)
+                    .map(|a| a.take_delayed_updates())
+                    .unwrap_or_default()
+            };
+            if !patch.is_empty() {
+                self.save_delayed_updates(&account_name, patch).await;
+            }
+        }
+    }
+
+    /// Logs out the account on this socket, after writing its delayed updates
+    pub async fn remove_online_account(&self, sid: Sid) {
+        let session_id = sid.to_string();
+        let Some(account_name) = self.online_account_name(&session_id).await else {
+            return;
+        };
+        let _write = self.account_writes.lock(&account_name).await;
+        let removed = {
+            let mut accounts = self.accounts.lock().await;
+            accounts
+                .iter()
+                .position(|a| a.id.as_deref() == Some(session_id.as_str()))
+                .map(|index| accounts.remove(index))
+        };
+        let Some(mut account) = removed else {
+            return;
+        };
+        self.server_info_changed.notify_one();
+        let patch = account.take_delayed_updates();
+        if !patch.is_empty() {
+            self.save_delayed_updates(&account.account_name, patch)
+                .await;
+        }
+    }
+
+    /// Call with the account's write lock held
+    async fn save_delayed_updates(&self, account_name: &str, patch: AccountPatch) {
+        let Err(e) = self.store.update(account_name, patch.clone()).await else {
+            return;
+        };
+        error!(account_name, "failed to save delayed updates: {e}");
+        // Keep them around for the next flush
+        let mut accounts = self.accounts.lock().await;
+        if let Some(account) = accounts.iter_mut().find(|a| a.account_name == account_name) {
+            account.restore_delayed_updates(patch);
+        }
+    }
+
+    pub fn spawn_delayed_update_flush(self: Arc<Self>) {
+        let interval = Duration::from_secs(self.config.delayed_update_flush_interval_secs);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                self.flush_delayed_updates().await;
+            }
+        });
+    }
+}
@@ -0,0 +1,241 @@
+use rand::distributions::{Alphanumeric, DistString};
+use serde_json::{Value, json};
+use socketioxide::extract::SocketRef;
+use std::time::{Duration, SystemTime};
+use tracing::error;
+
+use crate::{
+    common::protocol::{
+        AccountQueryResult, AccountUpdateEmailRequest, AccountVerifyEmailRequest, WriteError,
+        WriteOutcome,
+    },
+    models::account::{Account, EmailVerification},
+    server::BCServer,
+    storage::{AccountPatch, StoreError},
+    utilities::millis_timestamps::SystemTimeMillisTimestamps,
+};
+
+impl BCServer {
+    /// Creates a new verification token and the verification to store on the account.
+    /// The token must be mailed with `send_verification_mail` once the account is saved.
+    pub async fn new_email_verification(&self) -> Option<(String, EmailVerification)> {
+        let token = Alphanumeric.sample_string(&mut rand::thread_rng(), 32);
+        let cost = self.config.bcrypt_cost;
+        let hashed = tokio::task::spawn_blocking({
+            let token = token.clone();
+            move || bcrypt::hash(token, cost)
+        })
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|hash| hash.map_err(|e| e.to_string()));
+        let token_hash = match hashed {
+            Ok(h) => h,
+            Err(e) => {
+                error!("verification token hashing failed: {e}");
+                return None;
+            }
+        };
+        let ttl = Duration::from_secs(self.config.email_verification_ttl_hours * 3600);
+        let expires = SystemTime::now().get_timestamp_in_milliseconds() + ttl.as_millis() as i64;
+        Some((
+            token,
+            EmailVerification {
+                token_hash,
+                expires,
+            },
+        ))
+    }
+
+    pub async fn send_verification_mail(&self, email: &str, token: &str) {
+        let link = match &self.config.email_verification_url {
+            Some(url) => format!("\n\n{}", url.replace("{token}", token)),
+            None => String::new(),
+        };
+        let body = format!("Your Bondage Club email verification code is: {token}{link}");
+        if let Err(e) = self
+            .mailer
+            .send(email, "Bondage Club email verification", &body)
+            .await
+        {
+            error!("failed to send verification mail: {e}");
+        }
+    }
+
+    /// Checks if another account already uses this (normalized) email
+    pub async fn is_email_in_use(&self, email: &str) -> Result<bool, StoreError> {
+        Ok(self.store.find_by_email(email).await?.is_some())
+    }
+
+    pub async fn on_account_update_email(
+        &self,
+        socket: SocketRef,
+        request: AccountUpdateEmailRequest,
+    ) -> Result<WriteOutcome, WriteError> {
+        let respond = |result: bool| {
+            let _ = socket.emit(
+                "AccountQueryResult",
+                &AccountQueryResult::EmailUpdate(result),
+            );
+        };
+
+        let account_name;
+        {
+            let accounts = self.accounts.lock().await;
+            let account = accounts
+                .iter()
+                .find(|a| a.id == Some(socket.id.to_string()));
+            let Some(account) = account else {
+                return Err(WriteError::NotLoggedIn);
+            };
+            // The old email must match, unless the account never had one
+            let current = account.email.clone().unwrap_or_default();
+            if !current.is_empty() && current != Account::normalize_mail(&request.email_old) {
+                respond(false);
+                return Err(WriteError::Rejected);
+            }
+            account_name = account.account_name.clone();
+        }
+
+        let email = Account::normalize_mail(&request.email_new);
+        if !email.is_empty() && !Account::is_valid_mail(&email) {
+            respond(false);
+            return Err(WriteError::Rejected);
+        }
+
+        if !email.is_empty() {
+            match self.is_email_in_use(&email).await {
+                Ok(false) => {}
+                Ok(true) => {
+                    respond(false);
+                    return Err(WriteError::Rejected);
+                }
+                Err(err) => {
+                    error!("storage error while checking existing email: {err}");
+                    respond(false);
+                    return Err(WriteError::StorageFailure);
+                }
+            }
+        }
+
+        // Every new email has to be verified again
+        let verification = if email.is_empty() {
+            None
+        } else {
+            match self.new_email_verification().await {
+                Some(v) => Some(v),
+                None => {
+                    respond(false);
+                    return Err(WriteError::ServerError);
+                }
+            }
+        };
+
+        let mut update = AccountPatch::new();
+        update.insert("Email".into(), json!(email));
+        update.insert("EmailVerified".into(), json!(false));
+        update.insert(
+            "EmailVerification".into(),
+            json!(verification.as_ref().map(|(_, v)| v)),
+        );
+        if let Err(err) = self.store.update(&account_name, update).await {
+            error!("email update failed: {err}");
+            respond(false);
+            return Err(WriteError::StorageFailure);
+        }
+
+        {
+            let mut accounts = self.accounts.lock().await;
+            if let Some(account) = accounts
+                .iter_mut()
+                .find(|a| a.id == Some(socket.id.to_string()))
+            {
+                account.email = Some(email.clone());
+                account.email_verified = false;
+                account.email_verification = verification.as_ref().map(|(_, v)| v.clone());
+            }
+        }
+        respond(true);
+
+        if let Some((token, _)) = verification {
+            self.send_verification_mail(&email, &token).await;
+        }
+        Ok(WriteOutcome::Saved)
+    }
+
+    pub async fn on_account_verify_email(
+        &self,
+        socket: SocketRef,
+        request: AccountVerifyEmailRequest,
+    ) -> Result<WriteOutcome, WriteError> {
+        let respond = |result: bool| {
+            let _ = socket.emit(
+                "AccountQueryResult",
+                &AccountQueryResult::EmailVerify(result),
+            );
+        };
+
+        // The token is checked outside the lock, bcrypt would hold up every other account
+        let (account_name, verification) = {
+            let accounts = self.accounts.lock().await;
+            let account = accounts
+                .iter()
+                .find(|a| a.id == Some(socket.id.to_string()));
+            let Some(account) = account else {
+                return Err(WriteError::NotLoggedIn);
+            };
+            (
+                account.account_name.clone(),
+                account.email_verification.clone(),
+            )
+        };
+        let Some(verification) = verification else {
+            respond(false);
+            return Err(WriteError::Rejected);
+        };
+        if verification.expires < SystemTime::now().get_timestamp_in_milliseconds() {
+            respond(false);
+            return Err(WriteError::Rejected);
+        }
+        let token = request.token;
+        let token_hash = verification.token_hash;
+        let matches = tokio::task::spawn_blocking({
+            let token_hash = token_hash.clone();
+            move || bcrypt::verify(token, &token_hash).unwrap_or(false)
+        })
+        .await
+        .unwrap_or(false);
+        // The email may have changed meanwhile, its new token is the one to check then
+        let still_current = self.accounts.lock().await.iter().any(|a| {
+            a.id == Some(socket.id.to_string())
+                && a.email_verification
+                    .as_ref()
+                    .is_some_and(|v| v.token_hash == token_hash)
+        });
+        if !matches || !still_current {
+            respond(false);
+            return Err(WriteError::Rejected);
+        }
+
+        let mut update = AccountPatch::new();
+        update.insert("EmailVerified".into(), json!(true));
+        update.insert("EmailVerification".into(), Value::Null);
+        if let Err(err) = self.store.update(&account_name, update).await {
+            error!("email verification update failed: {err}");
+            respond(false);
+            return Err(WriteError::StorageFailure);
+        }
+
+        {
+            let mut accounts = self.accounts.lock().await;
+            if let Some(account) = accounts
+                .iter_mut()
+                .find(|a| a.id == Some(socket.id.to_string()))
+            {
+                account.email_verified = true;
+                account.email_verification = None;
+            }
+        }
+        respond(true);
+        Ok(WriteOutcome::Saved)
+    }
+}
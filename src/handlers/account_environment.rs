@@ -0,0 +1,156 @@
+use axum::http::HeaderMap;
+use rand::Rng;
+use socketioxide::extract::SocketRef;
+
+use crate::server::BCServer;
+
+/// Reduces an Origin or Referer header to `scheme://host[:port]`
+fn origin_of(url: &str) -> String {
+    let url = url.trim().to_lowercase();
+    let host_start = url.find("://").map(|i| i + 3).unwrap_or(0);
+    match url[host_start..].find('/') {
+        Some(i) => url[..host_start + i].to_string(),
+        None => url,
+    }
+}
+
+/// The environment of a client from its Origin header, or its Referer when it sent no Origin.
+/// `None` when the client has neither or its origin isn't listed.
+fn known_environment(
+    headers: &HeaderMap,
+    prod_origins: &[String],
+    dev_origins: &[String],
+) -> Option<&'static str> {
+    let origin = ["origin", "referer"]
+        .iter()
+        .filter_map(|name| headers.get(*name)?.to_str().ok())
+        .find(|h| !h.trim().is_empty())
+        .map(origin_of)?;
+
+    let matches = |origins: &[String]| origins.iter().any(|o| origin_of(o) == origin);
+    if matches(prod_origins) {
+        return Some("PROD");
+    }
+    // Without a list every other origin is a development build
+    if dev_origins.is_empty() || matches(dev_origins) {
+        return Some("DEV");
+    }
+    None
+}
+
+impl BCServer {
+    /// Finds out if the client runs on the production or a development build of the game.
+    /// Players only ever see others of the same environment.
+    pub fn account_get_environment(&self, socket: &SocketRef) -> String {
+        let headers = &socket.req_parts().headers;
+        match known_environment(headers, &self.config.prod_origins, &self.config.dev_origins) {
+            Some(environment) => environment.to_string(),
+            // Unknown clients get an environment of their own
+            None => rand::thread_rng()
+                .gen_range(0..1_000_000_000_000u64)
+                .to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn environment(headers: &[(&'static str, &str)], dev_origins: &[&str]) -> Option<&'static str> {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.insert(*name, value.parse().unwrap());
+        }
+        let prod = [PROD.to_string()];
+        let dev: Vec<String> = dev_origins.iter().map(|o| o.to_string()).collect();
+        known_environment(&map, &prod, &dev)
+    }
+
+    const PROD: &str = "https://www.bondageprojects.elementfx.com";
+    const DEV: &str = "http://localhost:8080";
+
+    #[test]
+    fn origins_are_compared_without_path_or_case() {
+        assert_eq!(
+            origin_of("HTTPS://Example.COM/game/?x=1"),
+            "https://example.com"
+        );
+        assert_eq!(
+            origin_of(" http://localhost:8080/ "),
+            "http://localhost:8080"
+        );
+        assert_eq!(origin_of("example.com/path"), "example.com");
+
+        for origin in [
+            PROD,
+            "https://WWW.BondageProjects.elementfx.com",
+            "https://www.bondageprojects.elementfx.com/R110/BondageClub/index.html?a=b",
+        ] {
+            assert_eq!(
+                environment(&[("origin", origin)], &[DEV]),
+                Some("PROD"),
+                "{origin}"
+            );
+        }
+        assert_eq!(
+            environment(&[("origin", "http://LOCALHOST:8080/")], &[DEV]),
+            Some("DEV")
+        );
+    }
+
+    #[test]
+    fn ports_tell_origins_apart() {
+        assert_eq!(
+            environment(
+                &[("origin", "https://www.bondageprojects.elementfx.com:8443")],
+                &[DEV]
+            ),
+            None
+        );
+        assert_eq!(
+            environment(&[("origin", "http://localhost:9090")], &[DEV]),
+            None
+        );
+    }
+
+    #[test]
+    fn origin_wins_over_referer() {
+        assert_eq!(
+            environment(
+                &[("origin", DEV), ("referer", &format!("{PROD}/index.html"))],
+                &[DEV]
+            ),
+            Some("DEV")
+        );
+        assert_eq!(
+            environment(&[("referer", &format!("{PROD}/index.html"))], &[DEV]),
+            Some("PROD")
+        );
+        // An empty Origin is the same as none
+        assert_eq!(
+            environment(&[("origin", ""), ("referer", PROD)], &[DEV]),
+            Some("PROD")
+        );
+    }
+
+    #[test]
+    fn any_other_origin_is_dev_without_a_dev_list() {
+        assert_eq!(
+            environment(&[("origin", "https://fork.example.com")], &[]),
+            Some("DEV")
+        );
+        assert_eq!(environment(&[("origin", PROD)], &[]), Some("PROD"));
+        assert_eq!(
+            environment(&[("origin", "https://fork.example.com")], &[DEV]),
+            None
+        );
+    }
+
+    #[test]
+    fn clients_without_an_origin_are_unknown() {
+        assert_eq!(environment(&[], &[]), None);
+        assert_eq!(environment(&[], &[DEV]), None);
+        assert_eq!(environment(&[("origin", " ")], &[]), None);
+    }
+}
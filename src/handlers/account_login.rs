@@ -1,33 +1,40 @@
-use async_recursion::async_recursion;
-use mongodb::bson::{Bson, doc};
+use metrics::histogram;
+use serde_json::json;
 use socketioxide::extract::SocketRef;
-use std::time::SystemTime;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{Instrument, error, info};
 
 use crate::{
     common::{
-        constants::{SERVER_ACCOUNT_NAME_REGEX, SERVER_ACCOUNT_PASSWORD_REGEX},
-        protocol::AccountLoginRequest,
-        types::LoginQueueStruct,
+        protocol::{AccountLoginRequest, LoginResponse},
+        types::{LoginQueueStruct, PendingTwoFactor},
     },
-    models::account::Account,
-    server::BCServer,
+    models::{account::Account, account_view::AccountSelfView},
+    monitoring::LOGIN_QUEUE_WAIT,
+    server::{BCServer, socket_span},
+    storage::AccountPatch,
     utilities::millis_timestamps::SystemTimeMillisTimestamps,
 };
 
 impl BCServer {
     pub async fn on_account_login(&self, socket: SocketRef, request: AccountLoginRequest) {
-        if !SERVER_ACCOUNT_NAME_REGEX.is_match(&request.account_name) {
-            println!(
-                "AccountCreate: Invalid AccountName: {}",
-                request.account_name
-            );
-            let _ = socket.emit("CreationResponse", "Invalid account name");
+        // Malformed credentials can't match any account
+        if !self
+            .config
+            .account_name_regex
+            .is_match(&request.account_name)
+            || !self
+                .config
+                .account_password_regex
+                .is_match(&request.password)
+        {
+            info!("invalid account name or password");
+            let _ = socket.emit("LoginResponse", &LoginResponse::InvalidNamePassword);
             return;
         }
 
-        if !SERVER_ACCOUNT_PASSWORD_REGEX.is_match(&request.password) {
-            println!("AccountCreate: Invalid Password");
-            let _ = socket.emit("CreationResponse", "Invalid password");
+        if self.is_shutting_down() {
+            let _ = socket.emit("LoginResponse", &LoginResponse::ServerShuttingDown);
             return;
         }
 
@@ -47,11 +54,12 @@ impl BCServer {
                     socket: socket.clone(),
                     account_name: uppercase_account_name,
                     password: request.clone().password,
+                    queued_at: Instant::now(),
                 },
             );
             pending_logins.insert(socket.id);
 
-            if login_queue.len() > 16 {
+            if login_queue.len() > self.config.login_queue_notify_threshold {
                 let _ = socket.emit("LoginQueue", &login_queue.len());
             }
         }
@@ -61,36 +69,49 @@ impl BCServer {
         }
     }
 
-    #[async_recursion]
     async fn account_login_run(&self) {
-        {
-            let mut login_queue = self.login_queue.write().await;
-            let mut pending_logins = self.pending_logins.write().await;
-            // Get next waiting login
-            if login_queue.is_empty() {
-                return;
+        loop {
+            let next = {
+                let mut pending_logins = self.pending_logins.write().await;
+                let mut login_queue = self.login_queue.write().await;
+                // Get next waiting login, skipping players who left while waiting
+                loop {
+                    let Some(sid) = pending_logins.first().copied() else {
+                        return;
+                    };
+                    let next = login_queue.get(&sid).unwrap().clone();
+                    if next.socket.connected() {
+                        break next;
+                    }
+                    pending_logins.remove(&sid);
+                    login_queue.remove(&sid);
+                }
             };
-            let mut next = login_queue.get(&pending_logins[0]).unwrap().clone();
 
-            while !next.socket.connected() {
-                // pop the first
-                pending_logins.remove(&next.socket.id);
-                login_queue.remove(&next.socket.id);
-                if login_queue.is_empty() {
-                    return;
-                }
-                next = login_queue.get(&pending_logins[0]).unwrap().clone();
+            histogram!(LOGIN_QUEUE_WAIT).record(next.queued_at.elapsed().as_secs_f64());
+            if self.is_shutting_down() {
+                let _ = next
+                    .socket
+                    .emit("LoginResponse", &LoginResponse::ServerShuttingDown);
+            } else {
+                // The queue runs in the event of whichever socket started it
+                let span = socket_span(&next.socket);
+                self.account_login_process(next.socket.clone(), next.account_name, next.password)
+                    .instrument(span)
+                    .await;
             }
-            self.account_login_process(next.socket.clone(), next.account_name, next.password)
-                .await;
-
-            pending_logins.remove(&next.socket.id);
-            login_queue.remove(&next.socket.id);
 
-            if !login_queue.is_empty() {
-                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
-                self.account_login_run().await;
+            let remaining = {
+                let mut pending_logins = self.pending_logins.write().await;
+                let mut login_queue = self.login_queue.write().await;
+                pending_logins.remove(&next.socket.id);
+                login_queue.remove(&next.socket.id);
+                login_queue.len()
+            };
+            if remaining == 0 {
+                return;
             }
+            tokio::time::sleep(Duration::from_millis(self.config.login_delay_ms)).await;
         }
     }
 
@@ -100,18 +121,13 @@ impl BCServer {
         account_name: String,
         password: String,
     ) {
-        let users: mongodb::Collection<Account> = self.db.collection(&self.config.db_accounts);
-
-        let account_result = users
-            .find_one(doc! { "AccountName": account_name }, None)
-            .await;
+        let account_result = self.store.find_by_name(&account_name).await;
         if !socket.connected() {
             return;
         }
-        if account_result.is_err() {
-            let error = account_result.unwrap_err();
-            println!("MongoDB error while checking existing account: {error}");
-            let _ = socket.emit("LoginResponse", "ServerError");
+        if let Err(error) = &account_result {
+            error!("storage error while checking existing account: {error}");
+            let _ = socket.emit("LoginResponse", &LoginResponse::ServerError);
             return;
         }
 
@@ -120,21 +136,18 @@ impl BCServer {
         }
         let account_result = account_result.unwrap();
         if account_result.is_none() {
-            let _ = socket.emit("LoginResponse", "InvalidNamePassword");
+            let _ = socket.emit("LoginResponse", &LoginResponse::InvalidNamePassword);
             return;
         }
 
-        let mut account_result = account_result.unwrap();
+        let account_result = account_result.unwrap();
 
         // Compare the password to its hashed version
-        let password_result = match bcrypt::verify(
-            password.to_uppercase(),
-            &account_result.password.clone().unwrap(),
-        ) {
+        let password_result = match account_result.verify_password(&password) {
             Ok(res) => res,
             Err(_) => {
-                println!("Password hashing failed");
-                let _ = socket.emit("LoginResponse", "ServerError");
+                error!("password hashing failed");
+                let _ = socket.emit("LoginResponse", &LoginResponse::ServerError);
                 return;
             }
         };
@@ -143,12 +156,57 @@ impl BCServer {
             return;
         }
         if !password_result {
-            let _ = socket.emit("LoginResponse", "InvalidNamePassword");
+            let _ = socket.emit("LoginResponse", &LoginResponse::InvalidNamePassword);
             return;
         }
 
+        if account_result
+            .ban
+            .as_ref()
+            .is_some_and(|b| b.is_active(SystemTime::now().get_timestamp_in_milliseconds()))
+        {
+            let _ = socket.emit("LoginResponse", &LoginResponse::AccountBanned);
+            return;
+        }
+
+        // Accounts with two-factor authentication finish their login with `AccountLoginTotp`
+        if account_result
+            .two_factor
+            .as_ref()
+            .is_some_and(|t| t.enabled)
+        {
+            let now = SystemTime::now();
+            let mut pending_two_factor = self.pending_two_factor.write().await;
+            pending_two_factor.retain(|_, pending| pending.expires > now);
+            pending_two_factor.insert(
+                socket.id,
+                PendingTwoFactor {
+                    account: account_result,
+                    attempts: 0,
+                    expires: now + Duration::from_secs(300),
+                },
+            );
+            let _ = socket.emit("LoginResponse", &LoginResponse::TwoFactorRequired);
+            return;
+        }
+
+        self.account_login_complete(socket, account_result).await;
+    }
+
+    /// Logs in an account that passed every check
+    pub async fn account_login_complete(&self, socket: SocketRef, mut account_result: Account) {
+        if self.is_shutting_down() {
+            let _ = socket.emit("LoginResponse", &LoginResponse::ServerShuttingDown);
+            return;
+        }
+
+        // The replaced session's delayed updates go out with this write, nothing may overtake it
+        let _write = self.account_writes.lock(&account_result.account_name).await;
+
         // Disconnect duplicated logged accounts
         // FIXME: literally don't know, built on hopes
+        let mut update = AccountPatch::new();
+        let mut delayed = AccountPatch::new();
         {
             let mut accounts = self.accounts.lock().await;
             for (index, account) in accounts.iter().enumerate() {
@@ -159,7 +217,13 @@ impl BCServer {
                     let socket = account.socket.as_ref().unwrap();
                     let _ = socket.emit("ForceDisconnect", "ErrorDuplicatedLogin");
                     let _ = <socketioxide::extract::SocketRef as Clone>::clone(socket).disconnect();
-                    accounts.remove(index);
+                    // The replaced session may still hold unsaved appearance, skill or game changes
+                    let mut previous = accounts.remove(index);
+                    delayed = previous.take_delayed_updates();
+                    update.extend(delayed.clone());
+                    account_result.appearance = previous.appearance;
+                    account_result.skill = previous.skill;
+                    account_result.game = previous.game;
                     break;
                 }
             }
@@ -169,29 +233,37 @@ impl BCServer {
 
         // Sets the last login date
         account_result.last_login = SystemTime::now().get_timestamp_in_milliseconds();
-        let _ = users
-            .update_one(
-                doc! { "AccountName": &account_result.account_name },
-                doc! { "$set": { "LastLogin": Bson::from(account_result.last_login) } },
-                None,
-            )
-            .await;
+        update.insert("LastLogin".into(), json!(account_result.last_login));
+        if let Err(e) = self
+            .store
+            .update(&account_result.account_name, update)
+            .await
+        {
+            // The new session carries them on, the next flush tries again
+            error!("failed to save the login: {e}");
+            account_result.restore_delayed_updates(delayed);
+        }
         account_result.id = Some(socket.id.to_string());
-        account_result.environment = "PROD".to_string(); //AccountGetEnvironment(socket);
+        account_result.environment = self.account_get_environment(&socket);
         // AccountValidData(account_result)
         // AccountRemoveFromChatRoom(account_result.MemberNumber);
+        account_result.socket = Some(socket.clone());
+        let span = socket_span(&socket);
+        span.record("account", account_result.account_name.as_str());
+        span.record("member_number", account_result.member_number);
+        info!("logged in");
         {
             let mut accounts = self.accounts.lock().await;
             accounts.push(account_result.clone());
         }
+        self.server_info_changed.notify_one();
         //OnLogin(socket);
-        let _ = socket.emit("LoginResponse", &account_result);
+        let _ = socket.emit("LoginResponse", &AccountSelfView::from(&account_result));
+        self.emit_server_info(socket).await;
 
         /* 	/** @type {Account|null} */
            Account.push(result);
            OnLogin(socket);
-           delete result.Password;
-           delete result.Email;
            socket.compress(false).emit("LoginResponse", result);
            result.Socket = socket;
            AccountSendServerInfo(socket);
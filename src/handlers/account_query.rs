@@ -1,10 +1,8 @@
-use mongodb::bson::doc;
-use serde_json::json;
 use socketioxide::extract::SocketRef;
 
 use crate::{
-    common::protocol::AccountQueryRequest,
-    models::account::{Account, ServerFriendInfo},
+    common::protocol::{AccountQueryRequest, AccountQueryResult},
+    models::account::ServerFriendInfo,
     server::BCServer,
 };
 
@@ -23,7 +21,10 @@ impl BCServer {
         if request.query == "OnlineFriends" {
             // Add all submissives owned by the player and all lovers of the players to the list
             let mut friends = vec![];
-            for account in accounts.iter() {
+            for account in accounts
+                .iter()
+                .filter(|a| a.environment == player.environment)
+            {
                 let is_owned = account.ownership.is_some()
                     && account.ownership.as_ref().unwrap().member_number == player.member_number;
 
@@ -88,40 +89,25 @@ impl BCServer {
             }
             let _ = socket.emit(
                 "AccountQueryResult",
-                &json!({ "Query": request.query, "Result": friends }),
+                &AccountQueryResult::OnlineFriends(friends),
             );
         }
 
+        // The client only wants to know if an email is set, never the email itself
         if request.query == "EmailStatus" {
-            let accounts: mongodb::Collection<Account> =
-                self.db.collection(&self.config.db_accounts);
-            let account_result = accounts
-                .find_one(
-                    doc! {
-                        "AccountName": player.account_name.clone(),
-                        "mail": { "$exists": true, "$ne": "" }
-                    },
-                    None,
-                )
-                .await;
-            match account_result {
-                Ok(Some(_account)) => {
-                    let _ = socket.emit(
-                        "AccountQueryResult",
-                        &json!({ "Query": request.query, "Result": true }),
-                    );
-                }
-                Ok(None) => {
-                    let _ = socket.emit(
-                        "AccountQueryResult",
-                        &json!({ "Query": request.query, "Result": false }),
-                    );
-                }
-                Err(err) => {
-                    println!("MongoDB error while checking existing account: {err}");
-                    let _ = socket.emit("AccountQueryResult", "Server error");
-                }
-            }
+            let has_email = player.email.as_ref().is_some_and(|e| !e.is_empty());
+            let _ = socket.emit(
+                "AccountQueryResult",
+                &AccountQueryResult::EmailStatus(has_email),
+            );
+        }
+
+        if request.query == "EmailVerified" {
+            let has_email = player.email.as_ref().is_some_and(|e| !e.is_empty());
+            let _ = socket.emit(
+                "AccountQueryResult",
+                &AccountQueryResult::EmailVerified(has_email && player.email_verified),
+            );
         }
     }
 }
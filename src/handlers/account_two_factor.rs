@@ -0,0 +1,489 @@
+use rand::distributions::{Alphanumeric, DistString};
+use serde_json::{Value, json};
+use socketioxide::extract::SocketRef;
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::{Mutex, OwnedMutexGuard};
+use totp_rs::{Algorithm, Secret, TOTP};
+use tracing::error;
+
+use crate::{
+    common::{
+        protocol::{LoginResponse, TwoFactorCodeRequest, WriteError, WriteOutcome},
+        types::TwoFactorFailures,
+    },
+    models::account::TwoFactor,
+    server::BCServer,
+    storage::{AccountPatch, StoreError},
+};
+
+const RECOVERY_CODE_COUNT: usize = 10;
+const MAX_TWO_FACTOR_ATTEMPTS: u8 = 5;
+/// Wrong codes an account takes over all its sockets before it has to wait
+const MAX_ACCOUNT_TWO_FACTOR_FAILURES: u8 = 10;
+const ACCOUNT_TWO_FACTOR_FAILURE_WINDOW: Duration = Duration::from_secs(15 * 60);
+const TOTP_STEP_SECS: u64 = 30;
+/// Codes of the steps next to the current one are accepted too, for clock drift
+const TOTP_SKEW: u64 = 1;
+
+fn build_totp(secret: &str, issuer: &str, account_name: &str) -> Option<TOTP> {
+    let secret = Secret::Encoded(secret.to_string()).to_bytes().ok()?;
+    TOTP::new(
+        Algorithm::SHA1,
+        6,
+        TOTP_SKEW as u8,
+        TOTP_STEP_SECS,
+        secret,
+        Some(issuer.to_string()),
+        account_name.to_string(),
+    )
+    .ok()
+}
+
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl TwoFactor {
+    /// Checks a TOTP code, or consumes a recovery code if it matches one.
+    /// A TOTP code is only accepted once, together with every code of an earlier step.
+    fn check_code(&mut self, code: &str, issuer: &str, account_name: &str, now: u64) -> bool {
+        let code = code.trim();
+        if let Some(totp) = build_totp(&self.secret, issuer, account_name) {
+            let current = now / TOTP_STEP_SECS;
+            for step in current.saturating_sub(TOTP_SKEW)..=current + TOTP_SKEW {
+                if self.last_used_step.is_some_and(|last| step <= last) {
+                    continue;
+                }
+                if constant_time_eq(&totp.generate(step * TOTP_STEP_SECS), code) {
+                    self.last_used_step = Some(step);
+                    return true;
+                }
+            }
+        }
+        let code = code.to_uppercase();
+        let used = self
+            .recovery_codes
+            .iter()
+            .position(|hash| bcrypt::verify(&code, hash).unwrap_or(false));
+        match used {
+            Some(index) => {
+                self.recovery_codes.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl BCServer {
+    fn emit_two_factor_result(&self, socket: &SocketRef, action: &str, result: Value) {
+        let _ = socket.emit(
+            "AccountTwoFactorResult",
+            &json!({ "Action": action, "Result": result }),
+        );
+    }
+
+    async fn save_two_factor(
+        &self,
+        account_name: &str,
+        two_factor: &Option<TwoFactor>,
+    ) -> Result<(), StoreError> {
+        let mut update = AccountPatch::new();
+        update.insert("TwoFactor".into(), json!(two_factor));
+        self.store.update(account_name, update).await
+    }
+
+    /// Two-factor settings of the account as stored, not as loaded by some earlier login
+    async fn load_two_factor(&self, account_name: &str) -> Result<Option<TwoFactor>, StoreError> {
+        Ok(self
+            .store
+            .find_by_name(account_name)
+            .await?
+            .and_then(|account| account.two_factor))
+    }
+
+    /// Locks the two-factor state of an account, hold it from reading the settings to saving them
+    async fn lock_two_factor(&self, account_name: &str) -> OwnedMutexGuard<TwoFactorFailures> {
+        let entry = {
+            let mut failures = self.two_factor_failures.lock().await;
+            let now = SystemTime::now();
+            failures.retain(|_, entry| {
+                Arc::strong_count(entry) > 1
+                    || entry.try_lock().is_ok_and(|f| {
+                        f.since
+                            .is_some_and(|since| since + ACCOUNT_TWO_FACTOR_FAILURE_WINDOW > now)
+                    })
+            });
+            failures
+                .entry(account_name.to_string())
+                .or_insert_with(|| Arc::new(Mutex::new(TwoFactorFailures::default())))
+                .clone()
+        };
+        entry.lock_owned().await
+    }
+
+    /// Checks a code off the async runtime, returns the updated settings if it was accepted
+    async fn verify_two_factor(
+        &self,
+        failures: &mut TwoFactorFailures,
+        mut two_factor: TwoFactor,
+        code: &str,
+        account_name: &str,
+    ) -> Option<TwoFactor> {
+        let now = SystemTime::now();
+        if failures
+            .since
+            .is_some_and(|since| since + ACCOUNT_TWO_FACTOR_FAILURE_WINDOW <= now)
+        {
+            *failures = TwoFactorFailures::default();
+        }
+        if failures.count >= MAX_ACCOUNT_TWO_FACTOR_FAILURES {
+            return None;
+        }
+
+        let code = code.to_string();
+        let issuer = self.config.totp_issuer.clone();
+        let name = account_name.to_string();
+        let checked = tokio::task::spawn_blocking(move || {
+            two_factor
+                .check_code(&code, &issuer, &name, unix_time())
+                .then_some(two_factor)
+        })
+        .await;
+        match checked {
+            Ok(Some(two_factor)) => {
+                *failures = TwoFactorFailures::default();
+                Some(two_factor)
+            }
+            Ok(None) => {
+                failures.count += 1;
+                failures.since.get_or_insert(now);
+                None
+            }
+            Err(e) => {
+                error!("two-factor check failed: {e}");
+                None
+            }
+        }
+    }
+
+    /// Stores the settings on the logged in account too, if it's still logged in on the socket
+    async fn set_logged_in_two_factor(&self, socket: &SocketRef, two_factor: Option<TwoFactor>) {
+        let mut accounts = self.accounts.lock().await;
+        if let Some(account) = accounts
+            .iter_mut()
+            .find(|a| a.id == Some(socket.id.to_string()))
+        {
+            account.two_factor = two_factor;
+        }
+    }
+
+    /// Generates a new secret, which stays inactive until a code is confirmed with it
+    pub async fn on_account_two_factor_setup(
+        &self,
+        socket: SocketRef,
+    ) -> Result<WriteOutcome, WriteError> {
+        let Some(account_name) = self.online_account_name(&socket.id.to_string()).await else {
+            return Err(WriteError::NotLoggedIn);
+        };
+        let _failures = self.lock_two_factor(&account_name).await;
+        match self.load_two_factor(&account_name).await {
+            Ok(two_factor) if two_factor.as_ref().is_some_and(|t| t.enabled) => {
+                self.emit_two_factor_result(&socket, "Setup", json!(false));
+                return Err(WriteError::Rejected);
+            }
+            Ok(_) => {}
+            Err(err) => {
+                error!("two-factor setup failed: {err}");
+                self.emit_two_factor_result(&socket, "Setup", json!(false));
+                return Err(WriteError::StorageFailure);
+            }
+        }
+
+        let secret = match Secret::generate_secret().to_encoded() {
+            Secret::Encoded(secret) => secret,
+            Secret::Raw(_) => unreachable!("to_encoded always returns an encoded secret"),
+        };
+        let Some(totp) = build_totp(&secret, &self.config.totp_issuer, &account_name) else {
+            self.emit_two_factor_result(&socket, "Setup", json!(false));
+            return Err(WriteError::ServerError);
+        };
+        let two_factor = Some(TwoFactor {
+            secret: secret.clone(),
+            enabled: false,
+            recovery_codes: vec![],
+            last_used_step: None,
+        });
+        if let Err(err) = self.save_two_factor(&account_name, &two_factor).await {
+            error!("two-factor setup failed: {err}");
+            self.emit_two_factor_result(&socket, "Setup", json!(false));
+            return Err(WriteError::StorageFailure);
+        }
+        self.set_logged_in_two_factor(&socket, two_factor).await;
+        self.emit_two_factor_result(
+            &socket,
+            "Setup",
+            json!({ "Secret": secret, "ProvisioningUri": totp.get_url() }),
+        );
+        Ok(WriteOutcome::Saved)
+    }
+
+    /// Enables two-factor authentication and hands out the recovery codes, only shown this once
+    pub async fn on_account_two_factor_confirm(
+        &self,
+        socket: SocketRef,
+        request: TwoFactorCodeRequest,
+    ) -> Result<WriteOutcome, WriteError> {
+        let Some(account_name) = self.online_account_name(&socket.id.to_string()).await else {
+            return Err(WriteError::NotLoggedIn);
+        };
+        let mut failures = self.lock_two_factor(&account_name).await;
+        let two_factor = match self.load_two_factor(&account_name).await {
+            Ok(two_factor) => two_factor,
+            Err(err) => {
+                error!("two-factor confirmation failed: {err}");
+                self.emit_two_factor_result(&socket, "Confirm", json!(false));
+                return Err(WriteError::StorageFailure);
+            }
+        };
+        let Some(two_factor) = two_factor.filter(|t| !t.enabled) else {
+            self.emit_two_factor_result(&socket, "Confirm", json!(false));
+            return Err(WriteError::Rejected);
+        };
+        let Some(mut two_factor) = self
+            .verify_two_factor(&mut failures, two_factor, &request.code, &account_name)
+            .await
+        else {
+            self.emit_two_factor_result(&socket, "Confirm", json!(false));
+            return Err(WriteError::Rejected);
+        };
+
+        let recovery_codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+            .map(|_| {
+                Alphanumeric
+                    .sample_string(&mut rand::thread_rng(), 10)
+                    .to_uppercase()
+            })
+            .collect();
+        let codes = recovery_codes.clone();
+        let cost = self.config.bcrypt_cost;
+        let hashes = tokio::task::spawn_blocking(move || {
+            codes
+                .iter()
+                .map(|code| bcrypt::hash(code, cost))
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|hashes| hashes.map_err(|e| e.to_string()));
+        match hashes {
+            Ok(hashes) => two_factor.recovery_codes = hashes,
+            Err(e) => {
+                error!("recovery code hashing failed: {e}");
+                self.emit_two_factor_result(&socket, "Confirm", json!(false));
+                return Err(WriteError::ServerError);
+            }
+        }
+        two_factor.enabled = true;
+
+        let two_factor = Some(two_factor);
+        if let Err(err) = self.save_two_factor(&account_name, &two_factor).await {
+            error!("two-factor confirmation failed: {err}");
+            self.emit_two_factor_result(&socket, "Confirm", json!(false));
+            return Err(WriteError::StorageFailure);
+        }
+        self.set_logged_in_two_factor(&socket, two_factor).await;
+        self.emit_two_factor_result(
+            &socket,
+            "Confirm",
+            json!({ "RecoveryCodes": recovery_codes }),
+        );
+        Ok(WriteOutcome::Saved)
+    }
+
+    pub async fn on_account_two_factor_disable(
+        &self,
+        socket: SocketRef,
+        request: TwoFactorCodeRequest,
+    ) -> Result<WriteOutcome, WriteError> {
+        let Some(account_name) = self.online_account_name(&socket.id.to_string()).await else {
+            return Err(WriteError::NotLoggedIn);
+        };
+        let mut failures = self.lock_two_factor(&account_name).await;
+        let two_factor = match self.load_two_factor(&account_name).await {
+            Ok(two_factor) => two_factor,
+            Err(err) => {
+                error!("two-factor removal failed: {err}");
+                self.emit_two_factor_result(&socket, "Disable", json!(false));
+                return Err(WriteError::StorageFailure);
+            }
+        };
+        let Some(two_factor) = two_factor.filter(|t| t.enabled) else {
+            self.emit_two_factor_result(&socket, "Disable", json!(false));
+            return Err(WriteError::Rejected);
+        };
+        if self
+            .verify_two_factor(&mut failures, two_factor, &request.code, &account_name)
+            .await
+            .is_none()
+        {
+            self.emit_two_factor_result(&socket, "Disable", json!(false));
+            return Err(WriteError::Rejected);
+        }
+        if let Err(err) = self.save_two_factor(&account_name, &None).await {
+            error!("two-factor removal failed: {err}");
+            self.emit_two_factor_result(&socket, "Disable", json!(false));
+            return Err(WriteError::StorageFailure);
+        }
+        self.set_logged_in_two_factor(&socket, None).await;
+        self.emit_two_factor_result(&socket, "Disable", json!(true));
+        Ok(WriteOutcome::Saved)
+    }
+
+    /// Second login step for accounts with two-factor authentication
+    pub async fn on_account_login_totp(&self, socket: SocketRef, request: TwoFactorCodeRequest) {
+        let (account_name, attempts) = {
+            let mut pending_two_factor = self.pending_two_factor.write().await;
+            let Some(pending) = pending_two_factor.get_mut(&socket.id) else {
+                return;
+            };
+            if pending.expires <= SystemTime::now() || pending.attempts >= MAX_TWO_FACTOR_ATTEMPTS {
+                pending_two_factor.remove(&socket.id);
+                let _ = socket.emit("LoginResponse", &LoginResponse::InvalidTwoFactorCode);
+                return;
+            }
+            // Counted before checking, so codes sent in parallel can't go past the limit
+            pending.attempts += 1;
+            (pending.account.account_name.clone(), pending.attempts)
+        };
+
+        let mut failures = self.lock_two_factor(&account_name).await;
+        let two_factor = match self.load_two_factor(&account_name).await {
+            Ok(two_factor) => two_factor.filter(|t| t.enabled),
+            Err(err) => {
+                error!("two-factor check failed: {err}");
+                let _ = socket.emit("LoginResponse", &LoginResponse::ServerError);
+                return;
+            }
+        };
+        let checked = match two_factor {
+            Some(two_factor) => {
+                self.verify_two_factor(&mut failures, two_factor, &request.code, &account_name)
+                    .await
+            }
+            None => None,
+        };
+
+        let pending = {
+            let mut pending_two_factor = self.pending_two_factor.write().await;
+            if checked.is_none() {
+                // Too many wrong codes, the whole login has to be done again
+                if attempts >= MAX_TWO_FACTOR_ATTEMPTS {
+                    pending_two_factor.remove(&socket.id);
+                }
+                let _ = socket.emit("LoginResponse", &LoginResponse::InvalidTwoFactorCode);
+                return;
+            }
+            pending_two_factor.remove(&socket.id)
+        };
+        // The socket left, or another code of it already finished the login
+        let Some(pending) = pending else {
+            return;
+        };
+
+        // The accepted step or a used up recovery code
+        if let Err(err) = self.save_two_factor(&account_name, &checked).await {
+            error!("two-factor update failed: {err}");
+            let _ = socket.emit("LoginResponse", &LoginResponse::ServerError);
+            return;
+        }
+        drop(failures);
+
+        let mut account = pending.account;
+        account.two_factor = checked;
+        account.id = Some(socket.id.to_string());
+        self.account_login_complete(socket, account).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "JBSWY3DPEHPK3PXPJBSWY3DPEHPK3PXP";
+    const NOW: u64 = 1_700_000_000;
+
+    fn two_factor(recovery_codes: &[&str]) -> TwoFactor {
+        TwoFactor {
+            secret: SECRET.to_string(),
+            enabled: true,
+            recovery_codes: recovery_codes
+                .iter()
+                .map(|code| bcrypt::hash(code, 4).unwrap())
+                .collect(),
+            last_used_step: None,
+        }
+    }
+
+    fn code_at(time: u64) -> String {
+        build_totp(SECRET, "Test", "ALICE").unwrap().generate(time)
+    }
+
+    #[test]
+    fn totp_code_is_accepted_once() {
+        let mut two_factor = two_factor(&[]);
+        let code = code_at(NOW);
+        assert!(two_factor.check_code(&code, "Test", "ALICE", NOW));
+        assert_eq!(two_factor.last_used_step, Some(NOW / TOTP_STEP_SECS));
+        assert!(!two_factor.check_code(&code, "Test", "ALICE", NOW));
+    }
+
+    #[test]
+    fn totp_code_of_an_earlier_step_is_refused() {
+        let mut two_factor = two_factor(&[]);
+        let earlier = code_at(NOW - TOTP_STEP_SECS);
+        assert!(two_factor.check_code(&code_at(NOW), "Test", "ALICE", NOW));
+        assert!(!two_factor.check_code(&earlier, "Test", "ALICE", NOW));
+        // The next step still works
+        let next = NOW + TOTP_STEP_SECS;
+        assert!(two_factor.check_code(&code_at(next), "Test", "ALICE", next));
+    }
+
+    #[test]
+    fn totp_code_outside_the_skew_is_refused() {
+        let mut two_factor = two_factor(&[]);
+        let old = code_at(NOW - 3 * TOTP_STEP_SECS);
+        assert!(!two_factor.check_code(&old, "Test", "ALICE", NOW));
+        assert!(!two_factor.check_code("000000x", "Test", "ALICE", NOW));
+    }
+
+    #[test]
+    fn recovery_code_is_consumed() {
+        let mut two_factor = two_factor(&["FIRSTCODE1", "SECONDCODE"]);
+        assert!(two_factor.check_code(" secondcode ", "Test", "ALICE", NOW));
+        assert_eq!(two_factor.recovery_codes.len(), 1);
+        assert!(bcrypt::verify("FIRSTCODE1", &two_factor.recovery_codes[0]).unwrap());
+    }
+
+    #[test]
+    fn reused_recovery_code_is_refused() {
+        let mut two_factor = two_factor(&["FIRSTCODE1"]);
+        assert!(two_factor.check_code("FIRSTCODE1", "Test", "ALICE", NOW));
+        assert!(!two_factor.check_code("FIRSTCODE1", "Test", "ALICE", NOW));
+        assert!(two_factor.recovery_codes.is_empty());
+    }
+}
@@ -1,111 +1,130 @@
-use mongodb::{
-    Collection,
-    bson::{self, Bson, Document, doc},
-};
+use serde_json::json;
 use socketioxide::extract::SocketRef;
+use tracing::{error, info};
 
 use crate::{
-    common::protocol::{Account, AccountUpdateRequest},
+    common::{
+        protocol::{AccountUpdateRequest, WriteError, WriteOutcome},
+        validation::FieldError,
+    },
+    models::character::{CharacterList, Lenient},
     server::BCServer,
+    storage::AccountPatch,
 };
 
 impl BCServer {
-    pub async fn on_account_update(&self, socket: SocketRef, request: AccountUpdateRequest) {
-        let mut update: Document = doc! {};
+    pub async fn on_account_update(
+        &self,
+        socket: SocketRef,
+        mut request: AccountUpdateRequest,
+    ) -> Result<WriteOutcome, WriteError> {
+        // Invalid fields are dropped from the update and reported back, valid ones are still saved
+        let errors = request.validate();
+        let result = self
+            .apply_account_update(&socket.id.to_string(), request, errors.clone())
+            .await;
+        if !errors.is_empty() && !matches!(result, Err(WriteError::NotLoggedIn)) {
+            info!(?errors, "rejected fields");
+            let _ = socket.emit(
+                "AccountUpdateResponse",
+                &json!({ "Result": "InvalidFields", "Errors": errors }),
+            );
+        }
+        result
+    }
+
+    /// Applies a validated update to the account logged in with the given session ID
+    async fn apply_account_update(
+        &self,
+        session_id: &str,
+        request: AccountUpdateRequest,
+        errors: Vec<FieldError>,
+    ) -> Result<WriteOutcome, WriteError> {
+        let Some(account_name) = self.online_account_name(session_id).await else {
+            return Err(WriteError::NotLoggedIn);
+        };
+        // A flush that took older delayed updates has to be written before this one
+        let _write = self.account_writes.lock(&account_name).await;
 
+        let mut update = AccountPatch::new();
         let mut accounts = self.accounts.lock().await;
         let account = accounts
             .iter_mut()
-            .find(|a| a.id == Some(socket.id.to_string()));
-        if account.is_none() {
-            return;
-        }
-        let account = account.unwrap();
+            .find(|a| a.id.as_deref() == Some(session_id));
+        let Some(account) = account else {
+            return Err(WriteError::NotLoggedIn);
+        };
+
         if let Some(log) = request.log.clone() {
-            update.insert("Log", bson::to_bson(&log).unwrap());
+            update.insert("Log".into(), json!(log));
             account.log = Some(log);
         }
         if let Some(inventory_data) = request.inventory_data {
-            update.insert("InventoryData", bson::to_bson(&inventory_data).unwrap());
+            update.insert("InventoryData".into(), json!(inventory_data));
             account.inventory_data = Some(inventory_data);
         }
         if let Some(item_permission) = request.item_permission {
             account.item_permission = item_permission;
-            update.insert("ItemPermission", Bson::from(item_permission as i32));
+            update.insert("ItemPermission".into(), json!(item_permission));
         }
         if let Some(arousal_settings) = request.arousal_settings {
-            update.insert("ArousalSettings", bson::to_bson(&arousal_settings).unwrap());
+            update.insert("ArousalSettings".into(), json!(arousal_settings));
             account.arousal_settings = Some(arousal_settings);
         }
         if let Some(online_shared_settings) = request.online_shared_settings {
-            update.insert(
-                "OnlineSharedSettings",
-                bson::to_bson(&online_shared_settings).unwrap(),
-            );
+            update.insert("OnlineSharedSettings".into(), json!(online_shared_settings));
             account.online_shared_settings = Some(online_shared_settings);
         }
-        if let Some(game) = request.game.clone() {
-            update.insert("Game", bson::to_bson(&game).unwrap());
-            account.game = Some(game);
-        }
         if let Some(map_data) = request.map_data {
             account.map_data = Some(map_data);
         }
         if let Some(label_color) = request.label_color {
-            update.insert("LabelColor", bson::to_bson(&label_color).unwrap());
+            update.insert("LabelColor".into(), json!(label_color));
             account.label_color = Some(label_color);
         }
-        if let Some(appearance) = request.appearance.clone() {
-            update.insert("Appearance", bson::to_bson(&appearance).unwrap());
-            account.appearance = Some(appearance);
-        }
         if let Some(reputation) = request.reputation {
-            update.insert("Reputation", bson::to_bson(&reputation).unwrap());
-            account.reputation = Some(reputation);
+            update.insert("Reputation".into(), json!(reputation));
+            account.reputation = Some(reputation.into());
         }
         if let Some(description) = request.description {
-            update.insert("Description", bson::to_bson(&description).unwrap());
+            update.insert("Description".into(), json!(description));
             account.description = Some(description);
         }
         if let Some(block_items) = request.block_items {
-            update.insert("BlockItems", bson::to_bson(&block_items).unwrap());
+            update.insert("BlockItems".into(), json!(block_items));
             account.block_items = Some(block_items);
         }
         if let Some(limited_items) = request.limited_items {
-            update.insert("LimitedItems", bson::to_bson(&limited_items).unwrap());
+            update.insert("LimitedItems".into(), json!(limited_items));
             account.limited_items = Some(limited_items);
         }
         if let Some(favorite_items) = request.favorite_items {
-            update.insert("FavoriteItems", bson::to_bson(&favorite_items).unwrap());
+            update.insert("FavoriteItems".into(), json!(favorite_items));
             account.favorite_items = Some(favorite_items);
         }
         if let Some(white_list) = request.white_list {
-            update.insert("WhiteList", bson::to_bson(&white_list).unwrap());
+            update.insert("WhiteList".into(), json!(white_list));
             account.white_list = white_list
         }
         if let Some(black_list) = request.black_list {
-            update.insert("BlackList", bson::to_bson(&black_list).unwrap());
+            update.insert("BlackList".into(), json!(black_list));
             account.black_list = black_list;
         }
         if let Some(friend_list) = request.friend_list {
-            update.insert("FriendList", bson::to_bson(&friend_list).unwrap());
+            update.insert("FriendList".into(), json!(friend_list));
             account.friend_list = friend_list;
         }
         // TODO: Lovership
-        if let Some(skill) = request.skill.clone() {
-            update.insert("Skill", bson::to_bson(&skill).unwrap());
-            account.skill = Some(skill);
-        }
         if let Some(title) = request.title {
-            update.insert("Title", bson::to_bson(&title).unwrap());
+            update.insert("Title".into(), json!(title));
             account.title = Some(title);
         }
         if let Some(nickname) = request.nickname {
-            update.insert("Nickname", bson::to_bson(&nickname).unwrap());
-            account.nickname = Some(nickname);
+            update.insert("Nickname".into(), json!(nickname));
+            account.nickname = (!nickname.is_null()).then_some(nickname);
         }
         if let Some(crafting) = request.crafting {
-            update.insert("Crafting", bson::to_bson(&crafting).unwrap());
+            update.insert("Crafting".into(), json!(crafting));
             account.crafting = Some(crafting);
         }
 
@@ -114,55 +133,281 @@ impl BCServer {
             // TODO: Chatroom
         }
 
-        // If only the appearance is updated, we keep the change in memory and do not update the database right away
-        /*     if let Some(appearance) = parsed.appearance.clone() {
-            update.insert("DelayedSkillUpdate", bson::to_bson(&appearance).unwrap());
-            account.delayed_appearance_update = Some(appearance).clone();
-            //console.log("TO REMOVE - Keeping Appearance in memory for account: " + Acc.AccountName);
-            return;
+        // Appearance, skill and game change all the time, so they are kept in memory and written
+        // by the periodic flush, or right away when the database has to be written anyway
+        let has_delayed_changes =
+            request.appearance.is_some() || request.skill.is_some() || request.game.is_some();
+        if let Some(appearance) = request.appearance {
+            let appearance = CharacterList::from(appearance);
+            account.appearance = Some(appearance.clone());
+            account.delayed_appearance_update = Some(appearance);
         }
-
-        // If only the skill is updated, we keep the change in memory and do not update the database right away
-        if let Some(skill) = parsed.skill {
-            update.insert("DelayedSkillUpdate", bson::to_bson(&skill).unwrap());
-            account.delayed_skill_update = Some(skill).clone();
-            //console.log("TO REMOVE - Keeping Skill in memory for account: " + Acc.AccountName);
-            return;
+        if let Some(skill) = request.skill {
+            let skill = CharacterList::from(skill);
+            account.skill = Some(skill.clone());
+            account.delayed_skill_update = Some(skill);
+        }
+        if let Some(game) = request.game {
+            account.game = Some(Lenient::Typed(game.clone()));
+            account.delayed_game_update = Some(Lenient::Typed(game));
         }
+        let outcome = if update.is_empty() {
+            // Delayed changes are only in memory for now, the ack tells they're not saved yet
+            if has_delayed_changes {
+                WriteOutcome::Pending
+            } else {
+                WriteOutcome::Saved
+            }
+        } else {
+            let delayed = account.take_delayed_updates();
+            update.extend(delayed.clone());
+            if let Err(e) = self.store.update(&account.account_name, update).await {
+                error!("failed to save: {e}");
+                account.restore_delayed_updates(delayed);
+                return Err(WriteError::StorageFailure);
+            }
+            WriteOutcome::Saved
+        };
+        if !errors.is_empty() {
+            return Err(WriteError::InvalidFields {
+                errors,
+                accepted: outcome,
+            });
+        }
+        Ok(outcome)
+    }
+}
 
-        // If only the game is updated, we keep the change in memory and do not update the database right away
-        if let Some(game) = parsed.game {
-            update.insert("DelayedGameUpdate", bson::to_bson(&game).unwrap());
-            account.delayed_game_update = Some(game).clone();
-            //console.log("TO REMOVE - Keeping Game in memory for account: " + Acc.AccountName);
-            return;
-        } */
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use serde_json::Value;
+    use socketioxide::SocketIo;
+    use std::{sync::Arc, time::Duration};
+    use tokio::sync::oneshot;
 
-        // Removes the delayed data to update if we update that property right now
-        /*   if let Some(appearance) = parsed.appearance.clone() {
-        if let Some(delayed_appearance_update) = account.delayed_appearance_update.clone() {
-            if delayed_appearance_update == appearance {
-                account.delayed_appearance_update = None;
-            }
-        } */
+    use crate::{
+        config::test_config,
+        models::{account::Account, account_migrations::MigrationReport},
+        storage::{AccountPage, AccountStore, StoreError, memory::MemoryAccountStore},
+    };
 
-        /*  if parsed.appearance.is_some() && account.delayed_appearance_update.is_some() {
-                update.remove("DelayedAppearanceUpdate");
-            }
-            if parsed.skill.is_some() && account.delayed_skill_update.is_some() {
-                update.remove("DelayedSkillUpdate");
+    type Pause = (oneshot::Sender<()>, oneshot::Receiver<()>);
+
+    /// Holds its first update until released, to let other writes try to overtake it
+    struct PausingStore {
+        inner: MemoryAccountStore,
+        pause: std::sync::Mutex<Option<Pause>>,
+    }
+
+    #[async_trait]
+    impl AccountStore for PausingStore {
+        async fn ping(&self) -> Result<(), StoreError> {
+            self.inner.ping().await
+        }
+        async fn find_by_name(&self, account_name: &str) -> Result<Option<Account>, StoreError> {
+            self.inner.find_by_name(account_name).await
+        }
+        async fn find_by_member_number(
+            &self,
+            member_number: u32,
+        ) -> Result<Option<Account>, StoreError> {
+            self.inner.find_by_member_number(member_number).await
+        }
+        async fn find_by_email(&self, email: &str) -> Result<Option<Account>, StoreError> {
+            self.inner.find_by_email(email).await
+        }
+        async fn insert(&self, account: &Account) -> Result<(), StoreError> {
+            self.inner.insert(account).await
+        }
+        async fn update(&self, account_name: &str, patch: AccountPatch) -> Result<(), StoreError> {
+            let pause = self.pause.lock().unwrap().take();
+            if let Some((entered, release)) = pause {
+                entered.send(()).unwrap();
+                release.await.unwrap();
             }
-            if parsed.game.is_some() && account.delayed_game_update.is_some() {
-                update.remove("DelayedGameUpdate");
+            self.inner.update(account_name, patch).await
+        }
+        async fn next_member_number(&self) -> Result<u32, StoreError> {
+            self.inner.next_member_number().await
+        }
+        async fn list_page(
+            &self,
+            after: Option<String>,
+            limit: usize,
+        ) -> Result<AccountPage, StoreError> {
+            self.inner.list_page(after, limit).await
+        }
+        async fn migrate_accounts(&self, dry_run: bool) -> Result<MigrationReport, StoreError> {
+            self.inner.migrate_accounts(dry_run).await
+        }
+    }
+
+    fn appearance(name: &str) -> Value {
+        json!([{ "Group": "Cloth", "Name": name }])
+    }
+
+    #[tokio::test]
+    async fn flush_and_update_of_one_account_are_written_in_order() {
+        let (entered_tx, entered_rx) = oneshot::channel();
+        let (release_tx, release_rx) = oneshot::channel();
+        let store = Arc::new(PausingStore {
+            inner: MemoryAccountStore::new(),
+            pause: std::sync::Mutex::new(Some((entered_tx, release_rx))),
+        });
+        let mut account = Account {
+            account_name: "ALICE".to_string(),
+            member_number: 1,
+            ..Default::default()
+        };
+        store.inner.insert(&account).await.unwrap();
+
+        let (_, io) = SocketIo::new_layer();
+        let server = BCServer::new(test_config(), store.clone(), io)
+            .await
+            .unwrap();
+        account.id = Some("session".to_string());
+        account.delayed_appearance_update = serde_json::from_value(appearance("Old")).unwrap();
+        server.accounts.lock().await.push(account);
+
+        let flush = tokio::spawn({
+            let server = server.clone();
+            async move { server.flush_delayed_updates().await }
+        });
+        // The flush took the old appearance and is writing it
+        entered_rx.await.unwrap();
+
+        let request: AccountUpdateRequest =
+            serde_json::from_value(json!({ "Appearance": appearance("New"), "Title": "Lady" }))
+                .unwrap();
+        let update = tokio::spawn({
+            let server = server.clone();
+            async move {
+                server
+                    .apply_account_update("session", request, vec![])
+                    .await
             }
-        }*/
-        let users: Collection<Account> = self.db.collection(&self.config.db_accounts);
-        let _ = users
-            .update_one(
-                doc! { "AccountName": &account.account_name },
-                doc! { "$set": update },
-                None,
-            )
-            .await;
+        });
+        // Long enough for the update to write first, if it could
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        release_tx.send(()).unwrap();
+        flush.await.unwrap();
+        update.await.unwrap().unwrap();
+
+        let stored = store.find_by_name("ALICE").await.unwrap().unwrap();
+        assert_eq!(json!(stored.appearance), appearance("New"));
+        assert_eq!(stored.title.as_deref(), Some("Lady"));
+    }
+
+    #[tokio::test]
+    async fn null_nickname_clears_it() {
+        let store = Arc::new(MemoryAccountStore::new());
+        let mut account = Account {
+            account_name: "ALICE".to_string(),
+            member_number: 1,
+            nickname: Some(json!("Kitty")),
+            ..Default::default()
+        };
+        store.insert(&account).await.unwrap();
+        let (_, io) = SocketIo::new_layer();
+        let server = BCServer::new(test_config(), store.clone(), io)
+            .await
+            .unwrap();
+        account.id = Some("session".to_string());
+        server.accounts.lock().await.push(account);
+
+        let request: AccountUpdateRequest =
+            serde_json::from_value(json!({ "Nickname": null })).unwrap();
+        server
+            .apply_account_update("session", request, vec![])
+            .await
+            .unwrap();
+
+        let stored = store.find_by_name("ALICE").await.unwrap().unwrap();
+        assert_eq!(stored.nickname, None);
+        assert_eq!(server.accounts.lock().await[0].nickname, None);
+    }
+
+    #[tokio::test]
+    async fn delayed_only_changes_are_pending() {
+        let store = Arc::new(MemoryAccountStore::new());
+        let mut account = Account {
+            account_name: "ALICE".to_string(),
+            member_number: 1,
+            ..Default::default()
+        };
+        store.insert(&account).await.unwrap();
+        let (_, io) = SocketIo::new_layer();
+        let server = BCServer::new(test_config(), store.clone(), io)
+            .await
+            .unwrap();
+        account.id = Some("session".to_string());
+        server.accounts.lock().await.push(account);
+        let update = |payload| async {
+            let request: AccountUpdateRequest = serde_json::from_value(payload).unwrap();
+            server
+                .apply_account_update("session", request, vec![])
+                .await
+                .unwrap()
+        };
+
+        assert_eq!(
+            update(json!({ "Appearance": appearance("Dress") })).await,
+            WriteOutcome::Pending
+        );
+        let stored = store.find_by_name("ALICE").await.unwrap().unwrap();
+        assert_eq!(stored.appearance, None);
+
+        // Written along with a change that goes to the database right away
+        assert_eq!(
+            update(json!({ "Title": "Lady" })).await,
+            WriteOutcome::Saved
+        );
+        let stored = store.find_by_name("ALICE").await.unwrap().unwrap();
+        assert_eq!(json!(stored.appearance), appearance("Dress"));
+    }
+
+    #[tokio::test]
+    async fn rejected_fields_still_tell_what_was_accepted() {
+        let store = Arc::new(MemoryAccountStore::new());
+        let mut account = Account {
+            account_name: "ALICE".to_string(),
+            member_number: 1,
+            ..Default::default()
+        };
+        store.insert(&account).await.unwrap();
+        let (_, io) = SocketIo::new_layer();
+        let server = BCServer::new(test_config(), store.clone(), io)
+            .await
+            .unwrap();
+        account.id = Some("session".to_string());
+        server.accounts.lock().await.push(account);
+        let update = |payload| async {
+            let mut request: AccountUpdateRequest = serde_json::from_value(payload).unwrap();
+            let errors = request.validate();
+            server
+                .apply_account_update("session", request, errors)
+                .await
+                .unwrap_err()
+        };
+
+        let result =
+            update(json!({ "Appearance": appearance("Dress"), "ItemPermission": 9 })).await;
+        let WriteError::InvalidFields { errors, accepted } = result else {
+            panic!("{result:?}");
+        };
+        assert_eq!(errors[0].field, "ItemPermission");
+        assert_eq!(accepted, WriteOutcome::Pending);
+
+        let result = update(json!({ "Title": "Lady", "ItemPermission": "high" })).await;
+        let WriteError::InvalidFields { errors, accepted } = result else {
+            panic!("{result:?}");
+        };
+        assert_eq!(errors[0].field, "ItemPermission");
+        assert_eq!(accepted, WriteOutcome::Saved);
+        let stored = store.find_by_name("ALICE").await.unwrap().unwrap();
+        assert_eq!(stored.title.as_deref(), Some("Lady"));
+        assert_eq!(json!(stored.appearance), appearance("Dress"));
     }
 }
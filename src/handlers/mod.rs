@@ -1,6 +1,11 @@
 pub mod account_beep;
 pub mod account_create;
+pub mod account_delayed_update;
+pub mod account_email;
+pub mod account_environment;
 pub mod account_login;
 pub mod account_query;
+pub mod account_two_factor;
 pub mod account_update;
 pub mod server_info;
+pub mod server_shutdown;
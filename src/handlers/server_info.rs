@@ -1,26 +1,67 @@
-use std::time::SystemTime;
+use serde::Serialize;
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
-use serde_json::json;
 use socketioxide::extract::SocketRef;
 
 use crate::{server::BCServer, utilities::millis_timestamps::SystemTimeMillisTimestamps};
 
+/// Logins come in bursts, a single push covers all of them
+const CHANGE_PUSH_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct ServerInfo {
+    pub time: i64,
+    pub online_players: usize,
+    /// Milliseconds since the server started
+    pub uptime: u64,
+    pub version: &'static str,
+}
+
 impl BCServer {
-    pub async fn emit_server_info(&self, socket: SocketRef) {
-        // Sends the info to all players
-
-        let server_info;
-        {
-            let accounts = self.accounts.lock().await;
-            server_info = json!( {
-                "Time": SystemTime::now().get_timestamp_in_milliseconds(),
-                "OnlinePlayers": accounts.len(),
-            })
+    pub async fn server_info(&self) -> ServerInfo {
+        ServerInfo {
+            time: SystemTime::now().get_timestamp_in_milliseconds(),
+            online_players: self.accounts.lock().await.len(),
+            uptime: self.started.elapsed().as_millis() as u64,
+            version: env!("CARGO_PKG_VERSION"),
         }
-        //if socket != None {
-        let _ = socket.emit("ServerInfo", &server_info);
-        // } else {
-        //IO.sockets.volatile.emit("ServerInfo", server_info);
-        //}
+    }
+
+    pub async fn emit_server_info(&self, socket: SocketRef) {
+        let _ = socket.emit("ServerInfo", &self.server_info().await);
+    }
+
+    /// Sends the info to every connected client
+    pub async fn broadcast_server_info(&self) {
+        let server_info = self.server_info().await;
+        let _ = self.io.emit("ServerInfo", &server_info).await;
+    }
+
+    pub fn spawn_server_info_broadcast(self: Arc<Self>) {
+        let interval = self.config.server_info_interval_secs;
+        tokio::spawn(async move {
+            loop {
+                let periodic = async {
+                    match interval {
+                        0 => std::future::pending().await,
+                        secs => tokio::time::sleep(Duration::from_secs(secs)).await,
+                    }
+                };
+                tokio::select! {
+                    _ = periodic => {}
+                    _ = self.server_info_changed.notified() => {
+                        tokio::time::sleep(CHANGE_PUSH_DELAY).await;
+                    }
+                }
+                if self.is_shutting_down() {
+                    return;
+                }
+                self.broadcast_server_info().await;
+            }
+        });
     }
 }
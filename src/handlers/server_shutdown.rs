@@ -0,0 +1,31 @@
+use std::{sync::atomic::Ordering, time::Duration};
+use tracing::info;
+
+use crate::server::BCServer;
+
+impl BCServer {
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::Relaxed)
+    }
+
+    /// Stops new logins and creations, lets the login queue run out, sends everyone away and
+    /// saves what is still only in memory
+    pub async fn shutdown(&self) {
+        self.shutting_down.store(true, Ordering::Relaxed);
+
+        // Queued logins are refused once their turn comes
+        while !self.login_queue.read().await.is_empty() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        let _ = self
+            .io
+            .emit("ServerMessage", &self.config.shutdown_message)
+            .await;
+        let _ = self.io.emit("ForceDisconnect", "ServerShutdown").await;
+        let _ = self.io.disconnect().await;
+
+        self.flush_delayed_updates().await;
+        info!("shutdown complete");
+    }
+}
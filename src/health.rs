@@ -0,0 +1,161 @@
+use axum::{Json, Router, extract::State, http::StatusCode, routing::get};
+use serde::Serialize;
+use std::{
+    sync::{Arc, atomic::Ordering},
+    time::{Duration, Instant},
+};
+
+use tracing::warn;
+
+use crate::server::BCServer;
+
+/// A store that doesn't answer within this is reported down
+const STORAGE_PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Ok,
+    Down,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Health {
+    pub status: Status,
+    /// Seconds since the server started
+    pub uptime: u64,
+    pub version: &'static str,
+}
+
+/// Anyone can read these, why a component is down only goes to the logs
+#[derive(Serialize, Debug)]
+pub struct ComponentStatus {
+    pub status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+}
+
+impl ComponentStatus {
+    fn check(ok: bool) -> Self {
+        Self {
+            status: if ok { Status::Ok } else { Status::Down },
+            latency_ms: None,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Readiness {
+    pub status: Status,
+    pub storage: ComponentStatus,
+    pub startup: ComponentStatus,
+    /// Down while the server is draining for a shutdown
+    pub shutdown: ComponentStatus,
+}
+
+impl BCServer {
+    /// Marks the startup as finished, the server is ready once its storage answers
+    pub fn finish_startup(&self) {
+        self.startup_finished.store(true, Ordering::Relaxed);
+    }
+
+    pub fn health(&self) -> Health {
+        Health {
+            status: Status::Ok,
+            uptime: self.started.elapsed().as_secs(),
+            version: env!("CARGO_PKG_VERSION"),
+        }
+    }
+
+    pub async fn readiness(&self) -> Readiness {
+        let started = Instant::now();
+        let storage = match tokio::time::timeout(STORAGE_PING_TIMEOUT, self.store.ping()).await {
+            Ok(Ok(())) => ComponentStatus {
+                latency_ms: Some(started.elapsed().as_millis() as u64),
+                ..ComponentStatus::check(true)
+            },
+            Ok(Err(e)) => {
+                warn!("storage is down: {e}");
+                ComponentStatus::check(false)
+            }
+            Err(_) => {
+                warn!("storage gave no answer within {STORAGE_PING_TIMEOUT:?}");
+                ComponentStatus::check(false)
+            }
+        };
+        let startup = ComponentStatus::check(self.startup_finished.load(Ordering::Relaxed));
+        let shutdown = ComponentStatus::check(!self.is_shutting_down());
+
+        let ready = [&storage, &startup, &shutdown]
+            .iter()
+            .all(|component| component.status == Status::Ok);
+        Readiness {
+            status: if ready { Status::Ok } else { Status::Down },
+            storage,
+            startup,
+            shutdown,
+        }
+    }
+}
+
+async fn healthz(State(server): State<Arc<BCServer>>) -> Json<Health> {
+    Json(server.health())
+}
+
+async fn readyz(State(server): State<Arc<BCServer>>) -> (StatusCode, Json<Readiness>) {
+    let readiness = server.readiness().await;
+    let code = match readiness.status {
+        Status::Ok => StatusCode::OK,
+        Status::Down => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (code, Json(readiness))
+}
+
+/// Serves `/healthz`, answered while the process runs, and `/readyz`, answered with 503 while
+/// the server can't take players
+pub fn router(server: Arc<BCServer>) -> Router {
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .with_state(server)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use socketioxide::SocketIo;
+
+    use crate::{config::test_config, storage::memory::MemoryAccountStore};
+
+    #[tokio::test]
+    async fn ready_between_startup_and_shutdown() {
+        let (_, io) = SocketIo::new_layer();
+        let server = BCServer::new(test_config(), Arc::new(MemoryAccountStore::new()), io)
+            .await
+            .unwrap();
+
+        let readiness = server.readiness().await;
+        assert_eq!(readiness.status, Status::Down);
+        assert_eq!(readiness.storage.status, Status::Ok);
+        assert_eq!(readiness.startup.status, Status::Down);
+
+        server.finish_startup();
+        let readiness = server.readiness().await;
+        assert_eq!(readiness.status, Status::Ok);
+        let latency = readiness.storage.latency_ms.unwrap();
+        assert_eq!(
+            serde_json::to_value(&readiness).unwrap(),
+            serde_json::json!({
+                "status": "ok",
+                "storage": { "status": "ok", "latency_ms": latency },
+                "startup": { "status": "ok" },
+                "shutdown": { "status": "ok" },
+            })
+        );
+
+        server.shutdown().await;
+        let readiness = server.readiness().await;
+        assert_eq!(readiness.status, Status::Down);
+        assert_eq!(readiness.shutdown.status, Status::Down);
+    }
+}
@@ -1,37 +1,75 @@
-use crate::server::{BCServer, load_config};
-use axum::{Router, extract::ConnectInfo, routing::get, serve};
-use axum_client_ip::ClientIpSource;
-use mongodb::{Client, options::ClientOptions};
+use crate::{
+    cli::{Cli, Command},
+    config::{AppConfig, LogFormat, load_config},
+    server::BCServer,
+    storage::{metered::MeteredAccountStore, open_store},
+    tls::{load_tls_config, redirect_router, spawn_certificate_reload},
+    utilities::client_ip::ClientIpResolver,
+};
+use axum::{
+    Extension, Router,
+    extract::ConnectInfo,
+    http::{HeaderMap, HeaderValue},
+    routing::get,
+    serve,
+};
+use clap::Parser;
 use socketioxide::SocketIo;
-use std::{net::SocketAddr, time::Duration};
-use tower_http::cors::{Any, CorsLayer};
-use tracing::info;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+use tracing::{error, info, warn};
+use tracing_subscriber::EnvFilter;
 
+mod admin;
+mod backup;
+mod cli;
 mod common;
+mod config;
 mod handlers;
+mod health;
+mod mailer;
 mod models;
+mod monitoring;
 mod server;
+mod storage;
+mod tls;
 mod utilities;
 
-async fn handler(ConnectInfo(addr): ConnectInfo<SocketAddr>) -> String {
-    addr.ip().to_string()
+async fn handler(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(resolver): Extension<Arc<ClientIpResolver>>,
+    headers: HeaderMap,
+) -> String {
+    resolver.resolve(addr.ip(), &headers).to_string()
 }
 
-pub fn init_socket_io() -> (Router, SocketIo) {
+pub fn init_socket_io(config: &AppConfig) -> (Router, SocketIo) {
     let (layer, io) = SocketIo::builder()
-        .max_buffer_size(180_000)
-        .ping_timeout(Duration::new(30, 0))
-        .ping_interval(Duration::new(50, 0))
-        .connect_timeout(Duration::new(5, 0))
+        .max_buffer_size(config.socket_max_buffer_size)
+        .ping_timeout(Duration::from_secs(config.socket_ping_timeout_secs))
+        .ping_interval(Duration::from_secs(config.socket_ping_interval_secs))
+        .connect_timeout(Duration::from_secs(config.socket_connect_timeout_secs))
         .build_layer();
 
+    let allow_origin = if config.cors_allowed_origins.is_empty() {
+        AllowOrigin::any()
+    } else {
+        // Every origin was checked when the config was loaded
+        AllowOrigin::list(
+            config
+                .cors_allowed_origins
+                .iter()
+                .filter_map(|origin| HeaderValue::from_str(origin).ok()),
+        )
+    };
+
     let router = Router::new()
         .route("/", get(handler))
         .layer(layer)
-        .layer(ClientIpSource::ConnectInfo.into_extension())
+        .layer(Extension(Arc::new(config.client_ip_resolver())))
         .layer(
             CorsLayer::new()
-                .allow_origin(Any)
+                .allow_origin(allow_origin)
                 .allow_methods(Any)
                 .allow_headers(Any),
         );
@@ -39,31 +77,183 @@ pub fn init_socket_io() -> (Router, SocketIo) {
     (router, io)
 }
 
+async fn bind(addr: &str) -> std::io::Result<tokio::net::TcpListener> {
+    tokio::net::TcpListener::bind(addr)
+        .await
+        .map_err(|e| std::io::Error::new(e.kind(), format!("failed to listen on {addr}: {e}")))
+}
+
+/// Serves a side listener in the background, it only stops when it failed
+fn spawn_listener(name: &'static str, listener: tokio::net::TcpListener, router: Router) {
+    tokio::spawn(async move {
+        match serve(listener, router).await {
+            Ok(()) => warn!("{name} listener stopped"),
+            Err(e) => error!("{name} listener failed: {e}"),
+        }
+    });
+}
+
+/// Logs go to stderr, admin commands write their output to stdout
+fn init_tracing(config: &AppConfig) {
+    let filter = EnvFilter::try_new(&config.log_filter).expect("Invalid log_filter");
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+    match config.log_format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
+}
+
+/// Resolves on Ctrl-C, or when the container is stopped
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to listen for Ctrl-C");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
     // load .env, config, logging, etc.
-    let config = load_config();
-    tracing_subscriber::fmt().init();
+    let config = match load_config(&cli.config) {
+        Ok(config) => config,
+        Err(e) => {
+            // Logging isn't set up yet
+            eprintln!("Invalid configuration: {e}");
+            std::process::exit(1);
+        }
+    };
+    init_tracing(&config);
+
+    let command = cli.command.unwrap_or_default();
+    #[cfg(feature = "sqlite")]
+    if let Command::MigrateMongoToSqlite = command {
+        storage::sqlite::migrate_mongo_to_sqlite(&config).await?;
+        return Ok(());
+    }
 
-    let db_uri: &str = &config.db_uri;
-    let db_name: &str = &config.db_name;
+    let serving = matches!(command, Command::Serve);
+    let store = match open_store(&config, serving).await {
+        Ok(store) => store,
+        Err(e) => {
+            error!("failed to open the account storage: {e}");
+            return Err(e.into());
+        }
+    };
 
-    let options = ClientOptions::parse(db_uri).await.unwrap();
-    let client = Client::with_options(options).unwrap();
-    let db = client.database(db_name);
+    if !serving {
+        return admin::run(store.as_ref(), &config, command).await;
+    }
 
-    let (socket_router, io) = init_socket_io();
+    let metrics_handle = config.metrics_enabled.then(monitoring::install_recorder);
+    let store = Arc::new(MeteredAccountStore::new(store));
 
-    let _server = BCServer::new(db, io).await;
+    let (socket_router, io) = init_socket_io(&config);
 
-    let app = Router::new()
+    let server = BCServer::new(config, store, io).await?;
+    let config = &server.config;
+
+    let mut app = Router::new().merge(health::router(server.clone()));
+    if let Some(handle) = metrics_handle {
+        let metrics_router = monitoring::router(server.clone(), handle);
+        match &config.metrics_addr {
+            Some(metrics_addr) => {
+                let listener = bind(metrics_addr).await?;
+                info!("serving metrics on {metrics_addr}");
+                spawn_listener("metrics", listener, metrics_router);
+            }
+            None => app = app.merge(metrics_router),
+        }
+    }
+    let app = app
         .merge(socket_router)
         .into_make_service_with_connect_info::<SocketAddr>();
 
-    let addr = config.server_addr;
-    let listener = tokio::net::TcpListener::bind(addr.clone()).await.unwrap();
-    info!("listening on {addr}");
-    serve(listener, app).await?;
+    let addr = &config.server_addr;
+    let socket_addr: SocketAddr = addr.parse()?;
+    let serving = async {
+        match (&config.tls_cert_path, &config.tls_key_path) {
+            (Some(cert_path), Some(key_path)) => {
+                let tls_config = load_tls_config(cert_path, key_path).await?;
+                spawn_certificate_reload(
+                    tls_config.clone(),
+                    cert_path.clone(),
+                    key_path.clone(),
+                    Duration::from_secs(config.tls_reload_interval_secs),
+                );
+
+                if let Some(redirect_addr) = &config.http_redirect_addr {
+                    let listener = bind(redirect_addr).await?;
+                    info!("redirecting http on {redirect_addr}");
+                    spawn_listener(
+                        "http redirect",
+                        listener,
+                        redirect_router(socket_addr.port()),
+                    );
+                }
+
+                info!("listening on {addr} with TLS");
+                server.finish_startup();
+                axum_server::bind_rustls(socket_addr, tls_config)
+                    .serve(app)
+                    .await?;
+            }
+            (None, None) => {
+                let listener = bind(addr).await?;
+                info!("listening on {addr}");
+                server.finish_startup();
+                serve(listener, app).await?;
+            }
+            _ => unreachable!("tls_cert_path and tls_key_path are checked when loading the config"),
+        }
+        Ok::<(), std::io::Error>(())
+    };
+
+    tokio::pin!(serving);
+    // Serving only stops by itself when it failed, the server still shuts down properly then
+    let mut served = tokio::select! {
+        result = &mut serving => Some(result),
+        _ = shutdown_signal() => {
+            info!("shutting down");
+            None
+        }
+    };
+    // Keep serving while draining, so `/readyz` reports it
+    let deadline = Duration::from_secs(config.shutdown_timeout_secs);
+    let shutdown = tokio::time::timeout(deadline, server.shutdown());
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            result = &mut shutdown => {
+                if result.is_err() {
+                    warn!("shutdown took longer than {deadline:?}, exiting anyway");
+                }
+                break;
+            }
+            result = &mut serving, if served.is_none() => served = Some(result),
+        }
+    }
+    if let Some(result) = served {
+        result?;
+    }
 
     Ok(())
 }
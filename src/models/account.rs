@@ -3,11 +3,15 @@ use std::collections::HashSet;
 // use mongodb::Database;
 // use ordermap::{OrderMap, OrderSet};
 // use serde::{self, Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{Value, json};
 // use socketioxide::{extract::SocketRef, socket::Sid};
 // use tokio::sync::RwLock;
 
-use crate::common::constants::SERVER_ACCOUNT_EMAIL_REGEX;
+use crate::{
+    common::constants::SERVER_ACCOUNT_EMAIL_REGEX,
+    models::character::{AppearanceItem, CharacterList, Game, Lenient, Reputation, Skill},
+    storage::AccountPatch,
+};
 use serde::{Deserialize, Serialize};
 use socketioxide::extract::SocketRef;
 
@@ -33,15 +37,69 @@ pub struct Ownership {
     pub start: i64,
 }
 
+/// A pending email verification, only the hash of the mailed token is stored
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct EmailVerification {
+    pub token_hash: String,
+    pub expires: i64,
+}
+
+/// TOTP settings, the login asks for a code once `enabled` is set
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct TwoFactor {
+    /// Base32 encoded TOTP secret
+    pub secret: String,
+    pub enabled: bool,
+    /// Hashes of the unused recovery codes
+    pub recovery_codes: Vec<String>,
+    /// Last TOTP time step a code was accepted for, codes of it and earlier steps are refused
+    #[serde(default)]
+    pub last_used_step: Option<u64>,
+}
+
+/// Staff roles, handed out with the admin CLI
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum AccountRole {
+    Admin,
+    Moderator,
+}
+
+/// Banned accounts can't log in until the ban expires, or forever without an end
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct Ban {
+    pub reason: String,
+    pub since: i64,
+    pub until: Option<i64>,
+}
+
+impl Ban {
+    pub fn is_active(&self, now: i64) -> bool {
+        self.until.is_none_or(|until| until > now)
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone, Default)]
 #[serde(rename_all = "PascalCase")]
 pub struct Account {
     #[serde(rename = "ID")]
     pub id: Option<String>,
     pub account_name: String,
+    /// Accounts saved before migrations existed are version 0
+    #[serde(default)]
+    pub schema_version: u32,
     pub name: String,
     pub password: Option<String>,
     pub email: Option<String>,
+    #[serde(default)]
+    pub email_verified: bool,
+    pub email_verification: Option<EmailVerification>,
+    pub two_factor: Option<TwoFactor>,
+    #[serde(default)]
+    pub roles: Vec<AccountRole>,
+    pub ban: Option<Ban>,
     pub member_number: u32,
     //pub lovership: Vec<Lovership>,
     pub item_permission: u8,
@@ -56,24 +114,24 @@ pub struct Account {
     pub socket: Option<SocketRef>,
     pub chat_room: Option<Value>,
     pub ownership: Option<Ownership>,
-    pub delayed_appearance_update: Option<Value>,
-    pub delayed_skill_update: Option<Value>,
-    pub delayed_game_update: Option<Value>,
+    pub delayed_appearance_update: Option<CharacterList<AppearanceItem>>,
+    pub delayed_skill_update: Option<CharacterList<Skill>>,
+    pub delayed_game_update: Option<Lenient<Game>>,
     pub inventory_data: Option<Value>,
     pub arousal_settings: Option<Value>,
     pub online_shared_settings: Option<Value>,
-    pub game: Option<Value>,
+    pub game: Option<Lenient<Game>>,
     pub map_data: Option<Value>,
     pub label_color: Option<Value>,
-    pub appearance: Option<Value>,
-    pub reputation: Option<Vec<String>>,
+    pub appearance: Option<CharacterList<AppearanceItem>>,
+    pub reputation: Option<CharacterList<Reputation>>,
     pub description: Option<String>,
     pub block_items: Option<Value>,
     pub limited_items: Option<Value>,
     pub favorite_items: Option<Value>,
     pub lovership: Option<Vec<Value>>,
     pub lover: Option<String>,
-    pub skill: Option<Value>,
+    pub skill: Option<CharacterList<Skill>>,
     pub title: Option<String>,
     pub nickname: Option<Value>,
     pub crafting: Option<Value>,
@@ -81,6 +139,67 @@ pub struct Account {
 }
 
 impl Account {
+    /// Takes the appearance, skill and game changes that weren't written to the database yet
+    pub fn take_delayed_updates(&mut self) -> AccountPatch {
+        let mut patch = AccountPatch::new();
+        if let Some(appearance) = self.delayed_appearance_update.take() {
+            patch.insert("Appearance".into(), json!(appearance));
+        }
+        if let Some(skill) = self.delayed_skill_update.take() {
+            patch.insert("Skill".into(), json!(skill));
+        }
+        if let Some(game) = self.delayed_game_update.take() {
+            patch.insert("Game".into(), json!(game));
+        }
+        patch
+    }
+
+    /// Puts back delayed changes that failed to save, unless newer ones arrived meanwhile
+    pub fn restore_delayed_updates(&mut self, mut patch: AccountPatch) {
+        // The patch was built by `take_delayed_updates`, so it always reads back
+        if let Some(Ok(appearance)) = patch.remove("Appearance").map(serde_json::from_value) {
+            self.delayed_appearance_update.get_or_insert(appearance);
+        }
+        if let Some(Ok(skill)) = patch.remove("Skill").map(serde_json::from_value) {
+            self.delayed_skill_update.get_or_insert(skill);
+        }
+        if let Some(Ok(game)) = patch.remove("Game").map(serde_json::from_value) {
+            self.delayed_game_update.get_or_insert(game);
+        }
+    }
+
+    /// Size of the changes waiting to be written, as serialized JSON
+    pub fn delayed_updates_size(&self) -> usize {
+        fn size(value: &Option<impl Serialize>) -> usize {
+            value
+                .as_ref()
+                .and_then(|v| serde_json::to_string(v).ok())
+                .map_or(0, |s| s.len())
+        }
+        size(&self.delayed_appearance_update)
+            + size(&self.delayed_skill_update)
+            + size(&self.delayed_game_update)
+    }
+
+    /// Passwords are case-insensitive, so they are hashed uppercased
+    pub fn hash_password(password: &str, cost: u32) -> Result<String, bcrypt::BcryptError> {
+        bcrypt::hash(password.to_uppercase(), cost)
+    }
+
+    /// Accounts imported from a redacted export have no password until an admin sets one,
+    /// nothing matches then
+    pub fn verify_password(&self, password: &str) -> Result<bool, bcrypt::BcryptError> {
+        match &self.password {
+            Some(hash) => bcrypt::verify(password.to_uppercase(), hash),
+            None => Ok(false),
+        }
+    }
+
+    /// Emails are stored trimmed and lowercased, so uniqueness checks can't be bypassed with casing
+    pub fn normalize_mail(mail: &str) -> String {
+        mail.trim().to_lowercase()
+    }
+
     pub fn is_valid_mail(mail: &str) -> bool {
         if !SERVER_ACCOUNT_EMAIL_REGEX.is_match(mail) {
             return false;
@@ -98,7 +217,7 @@ impl Account {
     }
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Debug)]
 #[serde(rename_all = "PascalCase")]
 pub struct ServerFriendInfo {
     pub r#type: &'static str, // "type" is a reserved keyword
@@ -0,0 +1,366 @@
+use serde_json::{Map, Value};
+use std::{collections::BTreeMap, fmt};
+
+use crate::models::account::Account;
+
+/// Upgrades a stored account one step, every step must be safe to run twice and tells
+/// whether it changed anything
+struct Migration {
+    /// Schema version of the account once this step ran
+    version: u32,
+    description: &'static str,
+    apply: fn(&mut Map<String, Value>) -> bool,
+}
+
+/// In order, the last one is the current schema version
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Lovership stored as a single object becomes a list",
+        apply: lovership_as_list,
+    },
+    Migration {
+        version: 2,
+        description: "Legacy Inventory moves to InventoryData",
+        apply: inventory_to_inventory_data,
+    },
+    Migration {
+        version: 3,
+        description: "MemberNumber stored as an integer",
+        apply: member_number_as_integer,
+    },
+    Migration {
+        version: 4,
+        description: "Email stored trimmed and lowercased",
+        apply: normalized_email,
+    },
+    Migration {
+        version: 5,
+        description: "Whole numbers stored as floats in Appearance, Reputation and Skill become integers",
+        apply: whole_numbers_as_integers,
+    },
+];
+
+pub const CURRENT_SCHEMA_VERSION: u32 = MIGRATIONS[MIGRATIONS.len() - 1].version;
+
+fn lovership_as_list(account: &mut Map<String, Value>) -> bool {
+    match account.get_mut("Lovership") {
+        Some(lovership) if lovership.is_object() => {
+            *lovership = Value::Array(vec![lovership.take()]);
+            true
+        }
+        _ => false,
+    }
+}
+
+fn inventory_to_inventory_data(account: &mut Map<String, Value>) -> bool {
+    let Some(inventory) = account.remove("Inventory") else {
+        return false;
+    };
+    // The newer field wins when both are around
+    if account.get("InventoryData").is_none_or(Value::is_null) {
+        account.insert("InventoryData".into(), inventory);
+    }
+    true
+}
+
+fn member_number_as_integer(account: &mut Map<String, Value>) -> bool {
+    let Some(member_number) = account.get_mut("MemberNumber") else {
+        return false;
+    };
+    let normalized = match member_number {
+        Value::Number(n) if !n.is_u64() => n
+            .as_f64()
+            .filter(|n| n.fract() == 0.0 && *n >= 0.0 && *n <= u32::MAX as f64)
+            .map(|n| n as u64),
+        Value::String(s) => s.trim().parse::<u32>().ok().map(u64::from),
+        _ => None,
+    };
+    match normalized {
+        Some(n) => {
+            *member_number = Value::from(n);
+            true
+        }
+        None => false,
+    }
+}
+
+fn normalized_email(account: &mut Map<String, Value>) -> bool {
+    match account.get_mut("Email") {
+        Some(Value::String(email)) if *email != Account::normalize_mail(email) => {
+            *email = Account::normalize_mail(email);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Typed list fields and the key holding each entry's integer
+const TYPED_NUMBERS: &[(&str, &str)] = &[
+    ("Appearance", "Difficulty"),
+    ("Reputation", "Value"),
+    ("Skill", "Level"),
+];
+
+fn whole_numbers_as_integers(account: &mut Map<String, Value>) -> bool {
+    let mut changed = false;
+    for (field, key) in TYPED_NUMBERS {
+        let Some(Value::Array(entries)) = account.get_mut(*field) else {
+            continue;
+        };
+        // The old server wrote some of them back as floats. Only whole numbers are converted,
+        // anything else is kept as it is and the entry stays untyped.
+        for n in entries.iter_mut().filter_map(|entry| entry.get_mut(*key)) {
+            if let Some(f) = n.as_f64()
+                && !n.is_i64()
+                && f.fract() == 0.0
+                && f >= f64::from(i32::MIN)
+                && f <= f64::from(i32::MAX)
+            {
+                *n = Value::from(f as i64);
+                changed = true;
+            }
+        }
+    }
+    changed
+}
+
+/// Runs every step newer than the account's schema version and returns the ones that changed
+/// something, or `None` when the account already is up to date
+pub fn migrate_account(account: &mut Map<String, Value>) -> Option<Vec<u32>> {
+    let version = account
+        .get("SchemaVersion")
+        .and_then(Value::as_u64)
+        .unwrap_or(0);
+    if version >= u64::from(CURRENT_SCHEMA_VERSION) {
+        return None;
+    }
+
+    let changed = MIGRATIONS
+        .iter()
+        .filter(|m| u64::from(m.version) > version)
+        .filter_map(|m| (m.apply)(account).then_some(m.version))
+        .collect();
+    account.insert("SchemaVersion".into(), Value::from(CURRENT_SCHEMA_VERSION));
+    Some(changed)
+}
+
+/// What a bulk migration did, or would do on a dry run
+#[derive(Debug, Default)]
+pub struct MigrationReport {
+    pub dry_run: bool,
+    pub scanned: usize,
+    pub outdated: usize,
+    /// Accounts per migration step
+    pub steps: BTreeMap<u32, usize>,
+    /// Accounts that couldn't be read or written
+    pub failed: Vec<String>,
+    /// Accounts sharing an email once it's normalized, keyed by that email. They are left as
+    /// they are, normalizing them would break the unique email index.
+    pub duplicate_emails: BTreeMap<String, Vec<String>>,
+}
+
+impl MigrationReport {
+    /// Finds the accounts whose emails collide once normalized, from account names and
+    /// stored emails
+    pub fn find_duplicate_emails(&mut self, emails: impl IntoIterator<Item = (String, String)>) {
+        let mut owners: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for (account_name, email) in emails {
+            let email = Account::normalize_mail(&email);
+            if !email.is_empty() {
+                owners.entry(email).or_default().push(account_name);
+            }
+        }
+        owners.retain(|_, accounts| accounts.len() > 1);
+        self.duplicate_emails = owners;
+    }
+
+    /// Whether the account is left out for sharing its email
+    pub fn has_duplicate_email(&self, account_name: &str) -> bool {
+        self.duplicate_emails
+            .values()
+            .any(|accounts| accounts.iter().any(|a| a == account_name))
+    }
+
+    pub fn record(&mut self, changed: Option<&[u32]>) {
+        self.scanned += 1;
+        let Some(changed) = changed else {
+            return;
+        };
+        self.outdated += 1;
+        for version in changed {
+            *self.steps.entry(*version).or_default() += 1;
+        }
+    }
+}
+
+impl fmt::Display for MigrationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let verb = if self.dry_run { "would be" } else { "were" };
+        writeln!(
+            f,
+            "{} of {} accounts {verb} upgraded to schema version {CURRENT_SCHEMA_VERSION}",
+            self.outdated, self.scanned
+        )?;
+        for migration in MIGRATIONS {
+            let count = self.steps.get(&migration.version).copied().unwrap_or(0);
+            writeln!(
+                f,
+                "  {}. {}: {count}",
+                migration.version, migration.description
+            )?;
+        }
+        if !self.failed.is_empty() {
+            writeln!(f, "  Failed: {}", self.failed.join(", "))?;
+        }
+        for (email, accounts) in &self.duplicate_emails {
+            writeln!(
+                f,
+                "  Left out, sharing the email {email}: {}",
+                accounts.join(", ")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Every legacy shape a step knows about
+    fn legacy_account() -> Map<String, Value> {
+        json!({
+            "AccountName": "ALICE",
+            "MemberNumber": "12",
+            "Lovership": { "Name": "Bob" },
+            "Inventory": "legacy inventory",
+            "Email": " Alice@Example.COM ",
+            "Appearance": [{ "Group": "Cloth", "Name": "Dress", "Difficulty": 2.0 }],
+            "Skill": [{ "Type": "Bondage", "Level": 3.0 }],
+        })
+        .as_object()
+        .cloned()
+        .unwrap()
+    }
+
+    #[test]
+    fn every_step_changes_nothing_the_second_time() {
+        for migration in MIGRATIONS {
+            let mut account = legacy_account();
+            assert!(
+                (migration.apply)(&mut account),
+                "step {} has nothing to do on the legacy account",
+                migration.version
+            );
+            let once = account.clone();
+            assert!(
+                !(migration.apply)(&mut account),
+                "step {} changed something the second time",
+                migration.version
+            );
+            assert_eq!(account, once, "step {}", migration.version);
+        }
+    }
+
+    #[test]
+    fn steps_leave_current_accounts_alone() {
+        let mut account = legacy_account();
+        migrate_account(&mut account);
+        let current = account.clone();
+        for migration in MIGRATIONS {
+            assert!(
+                !(migration.apply)(&mut account),
+                "step {}",
+                migration.version
+            );
+        }
+        assert_eq!(account, current);
+    }
+
+    #[test]
+    fn migrate_account_bumps_the_version_once() {
+        let mut account = legacy_account();
+        let changed = migrate_account(&mut account).unwrap();
+        assert_eq!(
+            changed,
+            MIGRATIONS.iter().map(|m| m.version).collect::<Vec<_>>()
+        );
+        assert_eq!(account["SchemaVersion"], json!(CURRENT_SCHEMA_VERSION));
+
+        let once = account.clone();
+        assert_eq!(migrate_account(&mut account), None);
+        assert_eq!(account, once);
+    }
+
+    #[test]
+    fn migrate_account_skips_steps_already_applied() {
+        let mut account = legacy_account();
+        account.insert("SchemaVersion".into(), json!(3));
+        let changed = migrate_account(&mut account).unwrap();
+        assert_eq!(changed, [4, 5]);
+        // Steps up to version 3 didn't run
+        assert_eq!(account["MemberNumber"], json!("12"));
+        assert!(account.contains_key("Inventory"));
+    }
+
+    #[test]
+    fn whole_numbers_keep_everything_else() {
+        let mut account = json!({
+            "Appearance": [
+                { "Group": "Cloth", "Name": "Dress", "Difficulty": 2.0 },
+                { "Group": "Cloth", "Name": "Skirt", "Difficulty": 2.5 },
+                { "Name": "No group", "Difficulty": null },
+                "not an item",
+            ],
+            "Reputation": "not a list",
+            "Skill": [{ "Type": "Bondage", "Level": 1e12 }],
+            "Game": { "LARP": 5 },
+        })
+        .as_object()
+        .cloned()
+        .unwrap();
+        let mut expected = account.clone();
+        expected["Appearance"][0]["Difficulty"] = json!(2);
+
+        assert!(whole_numbers_as_integers(&mut account));
+        assert_eq!(account, expected);
+        assert!(account["Appearance"][0]["Difficulty"].is_i64());
+    }
+
+    #[test]
+    fn duplicate_emails_are_found_after_normalizing() {
+        let mut report = MigrationReport::default();
+        report.find_duplicate_emails([
+            ("ALICE".to_string(), "Alice@Example.com".to_string()),
+            ("BOB".to_string(), " alice@example.com".to_string()),
+            ("CAROL".to_string(), "carol@example.com".to_string()),
+            ("DAVE".to_string(), "".to_string()),
+            ("EVE".to_string(), " ".to_string()),
+        ]);
+        assert_eq!(
+            report.duplicate_emails,
+            BTreeMap::from([(
+                "alice@example.com".to_string(),
+                vec!["ALICE".to_string(), "BOB".to_string()]
+            )])
+        );
+        assert!(report.has_duplicate_email("BOB"));
+        assert!(!report.has_duplicate_email("CAROL"));
+        assert!(report.to_string().contains("alice@example.com: ALICE, BOB"));
+    }
+
+    #[test]
+    fn report_counts_each_account_once() {
+        let mut report = MigrationReport::default();
+        for _ in 0..2 {
+            let mut account = legacy_account();
+            report.record(migrate_account(&mut account).as_deref());
+            report.record(migrate_account(&mut account).as_deref());
+        }
+        assert_eq!(report.scanned, 4);
+        assert_eq!(report.outdated, 2);
+        assert!(report.steps.values().all(|count| *count == 2));
+    }
+}
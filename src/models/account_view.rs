@@ -0,0 +1,295 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashSet;
+
+use crate::models::{
+    account::{Account, AccountRole, Ban, Ownership},
+    character::{AppearanceItem, CharacterList, Game, Lenient, Reputation, Skill},
+};
+
+// Every view lists its allowed fields explicitly instead of copying the account and removing
+// secrets, so a new field on `Account` is never sent to a client by accident.
+
+/// What players receive about their own account, on login
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct AccountSelfView<'a> {
+    #[serde(rename = "ID")]
+    pub id: &'a Option<String>,
+    pub account_name: &'a str,
+    pub name: &'a str,
+    pub member_number: u32,
+    pub item_permission: u8,
+    pub friend_list: &'a HashSet<u32>,
+    pub white_list: &'a HashSet<u32>,
+    pub black_list: &'a HashSet<u32>,
+    pub money: u32,
+    pub creation: i64,
+    pub last_login: i64,
+    pub environment: &'a str,
+    pub chat_room: &'a Option<Value>,
+    pub ownership: &'a Option<Ownership>,
+    pub inventory_data: &'a Option<Value>,
+    pub arousal_settings: &'a Option<Value>,
+    pub online_shared_settings: &'a Option<Value>,
+    pub game: &'a Option<Lenient<Game>>,
+    pub map_data: &'a Option<Value>,
+    pub label_color: &'a Option<Value>,
+    pub appearance: &'a Option<CharacterList<AppearanceItem>>,
+    pub reputation: &'a Option<CharacterList<Reputation>>,
+    pub description: &'a Option<String>,
+    pub block_items: &'a Option<Value>,
+    pub limited_items: &'a Option<Value>,
+    pub favorite_items: &'a Option<Value>,
+    pub lovership: &'a Option<Vec<Value>>,
+    pub lover: &'a Option<String>,
+    pub skill: &'a Option<CharacterList<Skill>>,
+    pub title: &'a Option<String>,
+    pub nickname: &'a Option<Value>,
+    pub crafting: &'a Option<Value>,
+    pub log: &'a Option<Vec<Value>>,
+}
+
+impl<'a> From<&'a Account> for AccountSelfView<'a> {
+    fn from(account: &'a Account) -> Self {
+        Self {
+            id: &account.id,
+            account_name: &account.account_name,
+            name: &account.name,
+            member_number: account.member_number,
+            item_permission: account.item_permission,
+            friend_list: &account.friend_list,
+            white_list: &account.white_list,
+            black_list: &account.black_list,
+            money: account.money,
+            creation: account.creation,
+            last_login: account.last_login,
+            environment: &account.environment,
+            chat_room: &account.chat_room,
+            ownership: &account.ownership,
+            inventory_data: &account.inventory_data,
+            arousal_settings: &account.arousal_settings,
+            online_shared_settings: &account.online_shared_settings,
+            game: &account.game,
+            map_data: &account.map_data,
+            label_color: &account.label_color,
+            appearance: &account.appearance,
+            reputation: &account.reputation,
+            description: &account.description,
+            block_items: &account.block_items,
+            limited_items: &account.limited_items,
+            favorite_items: &account.favorite_items,
+            lovership: &account.lovership,
+            lover: &account.lover,
+            skill: &account.skill,
+            title: &account.title,
+            nickname: &account.nickname,
+            crafting: &account.crafting,
+            log: &account.log,
+        }
+    }
+}
+
+/// What other players may see about a character, for chat room sync and friend lists
+#[allow(dead_code)] // No chat room sync yet
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct AccountPublicView<'a> {
+    #[serde(rename = "ID")]
+    pub id: &'a Option<String>,
+    pub name: &'a str,
+    pub member_number: u32,
+    pub item_permission: u8,
+    pub white_list: &'a HashSet<u32>,
+    pub black_list: &'a HashSet<u32>,
+    pub creation: i64,
+    pub ownership: &'a Option<Ownership>,
+    pub inventory_data: &'a Option<Value>,
+    pub arousal_settings: &'a Option<Value>,
+    pub online_shared_settings: &'a Option<Value>,
+    pub game: &'a Option<Lenient<Game>>,
+    pub map_data: &'a Option<Value>,
+    pub label_color: &'a Option<Value>,
+    pub appearance: &'a Option<CharacterList<AppearanceItem>>,
+    pub reputation: &'a Option<CharacterList<Reputation>>,
+    pub description: &'a Option<String>,
+    pub block_items: &'a Option<Value>,
+    pub limited_items: &'a Option<Value>,
+    pub favorite_items: &'a Option<Value>,
+    pub lovership: &'a Option<Vec<Value>>,
+    pub title: &'a Option<String>,
+    pub nickname: &'a Option<Value>,
+    pub crafting: &'a Option<Value>,
+}
+
+impl<'a> From<&'a Account> for AccountPublicView<'a> {
+    fn from(account: &'a Account) -> Self {
+        Self {
+            id: &account.id,
+            name: &account.name,
+            member_number: account.member_number,
+            item_permission: account.item_permission,
+            white_list: &account.white_list,
+            black_list: &account.black_list,
+            creation: account.creation,
+            ownership: &account.ownership,
+            inventory_data: &account.inventory_data,
+            arousal_settings: &account.arousal_settings,
+            online_shared_settings: &account.online_shared_settings,
+            game: &account.game,
+            map_data: &account.map_data,
+            label_color: &account.label_color,
+            appearance: &account.appearance,
+            reputation: &account.reputation,
+            description: &account.description,
+            block_items: &account.block_items,
+            limited_items: &account.limited_items,
+            favorite_items: &account.favorite_items,
+            lovership: &account.lovership,
+            title: &account.title,
+            nickname: &account.nickname,
+            crafting: &account.crafting,
+        }
+    }
+}
+
+/// What server staff may see about an account. Still never includes the password hash.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct AccountAdminView<'a> {
+    pub account_name: &'a str,
+    pub name: &'a str,
+    pub email: &'a Option<String>,
+    pub email_verified: bool,
+    pub member_number: u32,
+    pub roles: &'a [AccountRole],
+    pub ban: &'a Option<Ban>,
+    pub two_factor_enabled: bool,
+    pub item_permission: u8,
+    pub money: u32,
+    pub creation: i64,
+    pub last_login: i64,
+    pub environment: &'a str,
+    pub ownership: &'a Option<Ownership>,
+    pub lovership: &'a Option<Vec<Value>>,
+    pub friend_list: &'a HashSet<u32>,
+    pub white_list: &'a HashSet<u32>,
+    pub black_list: &'a HashSet<u32>,
+}
+
+impl<'a> From<&'a Account> for AccountAdminView<'a> {
+    fn from(account: &'a Account) -> Self {
+        Self {
+            account_name: &account.account_name,
+            name: &account.name,
+            email: &account.email,
+            email_verified: account.email_verified,
+            member_number: account.member_number,
+            roles: &account.roles,
+            ban: &account.ban,
+            two_factor_enabled: account.two_factor.as_ref().is_some_and(|t| t.enabled),
+            item_permission: account.item_permission,
+            money: account.money,
+            creation: account.creation,
+            last_login: account.last_login,
+            environment: &account.environment,
+            ownership: &account.ownership,
+            lovership: &account.lovership,
+            friend_list: &account.friend_list,
+            white_list: &account.white_list,
+            black_list: &account.black_list,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::account::{EmailVerification, TwoFactor};
+    use serde_json::Map;
+
+    const SECRETS: [&str; 4] = [
+        "$2b$10$passwordhash",
+        "JBSWY3DPEHPK3PXP",
+        "$2b$10$recoverycode",
+        "$2b$10$emailtoken",
+    ];
+    const SECRET_KEYS: [&str; 3] = ["Password", "TwoFactor", "EmailVerification"];
+
+    fn account() -> Account {
+        Account {
+            account_name: "ALICE".to_string(),
+            name: "Alice".to_string(),
+            password: Some("$2b$10$passwordhash".to_string()),
+            email: Some("alice@example.com".to_string()),
+            email_verification: Some(EmailVerification {
+                token_hash: "$2b$10$emailtoken".to_string(),
+                expires: 0,
+            }),
+            two_factor: Some(TwoFactor {
+                secret: "JBSWY3DPEHPK3PXP".to_string(),
+                enabled: true,
+                recovery_codes: vec!["$2b$10$recoverycode".to_string()],
+                last_used_step: Some(1),
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn fields(view: &impl Serialize) -> (Map<String, Value>, String) {
+        let value = serde_json::to_value(view).unwrap();
+        let text = value.to_string();
+        (value.as_object().unwrap().clone(), text)
+    }
+
+    #[test]
+    fn self_view_has_no_secrets() {
+        let account = account();
+        let (fields, text) = fields(&AccountSelfView::from(&account));
+        for secret in SECRETS {
+            assert!(!text.contains(secret), "{secret} leaked");
+        }
+        for key in [
+            "EmailVerification",
+            "DelayedAppearanceUpdate",
+            "SchemaVersion",
+        ] {
+            assert!(!fields.contains_key(key), "{key} leaked");
+        }
+        assert_eq!(fields["AccountName"], "ALICE");
+    }
+
+    #[test]
+    fn public_view_has_no_secrets() {
+        let account = account();
+        let (fields, text) = fields(&AccountPublicView::from(&account));
+        for secret in SECRETS.iter().chain(&["alice@example.com"]) {
+            assert!(!text.contains(secret), "{secret} leaked");
+        }
+        for key in SECRET_KEYS.iter().chain(&[
+            "AccountName",
+            "Email",
+            "EmailVerified",
+            "TwoFactorEnabled",
+            "FriendList",
+            "Money",
+        ]) {
+            assert!(!fields.contains_key(*key), "{key} leaked");
+        }
+        assert_eq!(fields["Name"], "Alice");
+    }
+
+    #[test]
+    fn admin_view_has_no_secrets() {
+        let account = account();
+        let (fields, text) = fields(&AccountAdminView::from(&account));
+        for secret in SECRETS {
+            assert!(!text.contains(secret), "{secret} leaked");
+        }
+        for key in SECRET_KEYS {
+            assert!(!fields.contains_key(key), "{key} leaked");
+        }
+        assert_eq!(fields["Email"], "alice@example.com");
+        assert_eq!(fields["TwoFactorEnabled"], true);
+    }
+}
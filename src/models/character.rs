@@ -0,0 +1,289 @@
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{Map, Number, Value};
+
+// The client keeps adding properties to these, everything the server doesn't know about is kept
+// in `extra` and written back untouched. An explicit null is kept apart from a missing key, and
+// whatever doesn't read as its type at all is kept as `Lenient::Raw`.
+
+/// A stored value as it was written when it doesn't read as `T`, so nothing is lost
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Lenient<T> {
+    Typed(T),
+    Raw(Value),
+}
+
+/// Appearance, reputation and skill lists as stored: the list itself or any of its entries
+/// may not read as typed
+pub type CharacterList<T> = Lenient<Vec<Lenient<T>>>;
+
+impl<T> From<Vec<T>> for CharacterList<T> {
+    fn from(entries: Vec<T>) -> Self {
+        Self::Typed(entries.into_iter().map(Lenient::Typed).collect())
+    }
+}
+
+/// Reads a key that is present as `Some`, even when it holds null
+fn present<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+/// Color of a worn item, one for the whole item or one per layer
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum ItemColor {
+    Single(String),
+    Layers(Vec<String>),
+}
+
+/// An item worn by the character, clothes and restraints alike
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+pub struct AppearanceItem {
+    pub group: String,
+    pub name: String,
+    #[serde(
+        default,
+        deserialize_with = "present",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub color: Option<Option<ItemColor>>,
+    #[serde(
+        default,
+        deserialize_with = "present",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub difficulty: Option<Option<i32>>,
+    /// Item specific state: lock, type, effects...
+    #[serde(
+        default,
+        deserialize_with = "present",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub property: Option<Option<Map<String, Value>>>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+pub struct Skill {
+    pub r#type: String,
+    pub level: i32,
+    /// Share of the level actually applied, the client lets players lower their skills. Kept as
+    /// the number the client sent, `1` and `1.0` both show up.
+    #[serde(
+        default,
+        deserialize_with = "present",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub ratio: Option<Option<Number>>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+pub struct Reputation {
+    pub r#type: String,
+    pub value: i32,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// Progress in the club's mini games, each game keeps its own settings and stats
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Default)]
+#[serde(rename_all = "PascalCase")]
+pub struct Game {
+    #[serde(
+        rename = "LARP",
+        default,
+        deserialize_with = "present",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub larp: Option<Option<Map<String, Value>>>,
+    #[serde(
+        default,
+        deserialize_with = "present",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub magic_battle: Option<Option<Map<String, Value>>>,
+    #[serde(
+        rename = "GGTS",
+        default,
+        deserialize_with = "present",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub ggts: Option<Option<Map<String, Value>>>,
+    #[serde(
+        default,
+        deserialize_with = "present",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub poker: Option<Option<Map<String, Value>>>,
+    #[serde(
+        default,
+        deserialize_with = "present",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub club_card: Option<Option<Map<String, Value>>>,
+    #[serde(
+        default,
+        deserialize_with = "present",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub prison: Option<Option<Map<String, Value>>>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::account::Account;
+    use serde::de::DeserializeOwned;
+    use serde_json::json;
+
+    impl<T> Lenient<T> {
+        fn typed(&self) -> Option<&T> {
+            match self {
+                Self::Typed(typed) => Some(typed),
+                Self::Raw(_) => None,
+            }
+        }
+    }
+
+    fn round_trip<T: DeserializeOwned + Serialize>(stored: Value) -> (T, Value) {
+        let typed: T = serde_json::from_value(stored).unwrap();
+        let written = serde_json::to_value(&typed).unwrap();
+        (typed, written)
+    }
+
+    #[test]
+    fn appearance_reads_back_as_written() {
+        let stored = json!([
+            {
+                "Group": "ItemArms",
+                "Name": "HempRope",
+                "Color": ["#A07858", "Default"],
+                "Difficulty": 3,
+                "Property": {
+                    "TypeRecord": { "typed": 2 },
+                    "LockedBy": "MistressPadlock",
+                    "LockMemberNumber": 1234,
+                    "Effect": ["Block", "Prone"],
+                },
+                "Craft": { "Name": "Tight rope", "MemberNumber": 1234 },
+            },
+            { "Group": "Cloth", "Name": "Dress", "Color": "Default", "Difficulty": null, "Property": null },
+            { "Group": "HairFront", "Name": "HairFront1", "Color": null },
+            // Not an item this server can read, kept anyway
+            { "Name": "No group", "Difficulty": 2.5 },
+            "not an item",
+        ]);
+        let (items, written) = round_trip::<CharacterList<AppearanceItem>>(stored.clone());
+        assert_eq!(written, stored);
+
+        let Lenient::Typed(items) = items else {
+            panic!("the list reads as a list");
+        };
+        assert_eq!(items.iter().filter(|i| i.typed().is_some()).count(), 3);
+        let rope = items[0].typed().unwrap();
+        assert_eq!(rope.difficulty, Some(Some(3)));
+        assert!(rope.extra.contains_key("Craft"));
+        assert_eq!(items[1].typed().unwrap().difficulty, Some(None));
+        assert_eq!(items[2].typed().unwrap().difficulty, None);
+    }
+
+    #[test]
+    fn skill_and_reputation_read_back_as_written() {
+        let stored = json!([
+            { "Type": "Bondage", "Level": 5, "Progress": 120 },
+            { "Type": "Evasion", "Level": 2, "Ratio": 0.5 },
+            { "Type": "Dressage", "Level": 1, "Ratio": 1 },
+            { "Type": "Willpower", "Level": 3, "Ratio": null },
+        ]);
+        let (_, written) = round_trip::<CharacterList<Skill>>(stored.clone());
+        assert_eq!(written, stored);
+
+        let stored = json!([
+            { "Type": "Dominant", "Value": -40 },
+            { "Type": "Kidnap", "Value": 12, "Since": 1700000000000_i64 },
+            { "Type": "Gaming", "Value": 7.5 },
+        ]);
+        let (_, written) = round_trip::<CharacterList<Reputation>>(stored.clone());
+        assert_eq!(written, stored);
+
+        // Not a list at all
+        let (list, written) = round_trip::<CharacterList<Reputation>>(json!("not a list"));
+        assert_eq!(written, json!("not a list"));
+        assert!(list.typed().is_none());
+    }
+
+    #[test]
+    fn game_reads_back_as_written() {
+        let stored = json!({
+            "LARP": { "Class": "Fighter", "Level": { "Fighter": 2 } },
+            "MagicBattle": null,
+            "GGTS": { "Level": 3, "Time": 123456, "Strike": 0 },
+            "ClubCard": { "Deck": ["1", "2"], "Reward": "" },
+            "Pandora": { "Progress": 2 },
+        });
+        let (game, written) = round_trip::<Lenient<Game>>(stored.clone());
+        assert_eq!(written, stored);
+        let game = game.typed().unwrap();
+        assert_eq!(game.magic_battle, Some(None));
+        assert_eq!(game.poker, None);
+        assert!(game.extra.contains_key("Pandora"));
+
+        let stored = json!({ "LARP": 5, "Poker": { "Mode": "Strip" } });
+        let (game, written) = round_trip::<Lenient<Game>>(stored.clone());
+        assert_eq!(written, stored);
+        assert!(game.typed().is_none());
+    }
+
+    #[test]
+    fn stored_account_keeps_its_character_fields() {
+        let stored = json!({
+            "AccountName": "ALICE",
+            "Name": "Alice",
+            "MemberNumber": 1234,
+            "ItemPermission": 2,
+            "FriendList": [],
+            "WhiteList": [],
+            "BlackList": [],
+            "Money": 100,
+            "Creation": 1700000000000_i64,
+            "LastLogin": 1700000000000_i64,
+            "Environment": "PROD",
+            "Appearance": [
+                { "Group": "BodyUpper", "Name": "Normal", "Color": "White" },
+                { "Group": "ItemNeck", "Name": "LeatherCollar", "Difficulty": null, "Property": { "LockedBy": "OwnerPadlock" } },
+                { "Group": "Cloth", "Difficulty": 1.5 },
+            ],
+            "Reputation": [{ "Type": "Dominant", "Value": 50 }],
+            "Skill": [{ "Type": "Bondage", "Level": 4, "Ratio": null }],
+            "Game": { "LARP": 5 },
+            "DelayedAppearanceUpdate": [{ "Group": "Hat", "Name": "Beret", "Color": null }],
+            "DelayedSkillUpdate": "not a list",
+            "DelayedGameUpdate": { "Poker": null },
+        });
+        let account: Account = serde_json::from_value(stored.clone()).unwrap();
+        let written = serde_json::to_value(&account).unwrap();
+        for field in [
+            "Appearance",
+            "Reputation",
+            "Skill",
+            "Game",
+            "DelayedAppearanceUpdate",
+            "DelayedSkillUpdate",
+            "DelayedGameUpdate",
+        ] {
+            assert_eq!(written[field], stored[field], "{field}");
+        }
+    }
+}
@@ -1 +1,4 @@
 pub mod account;
+pub mod account_migrations;
+pub mod account_view;
+pub mod character;
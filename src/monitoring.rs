@@ -0,0 +1,98 @@
+use axum::{Router, extract::State, routing::get};
+use metrics::{Unit, describe_counter, describe_gauge, describe_histogram, gauge};
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use std::sync::Arc;
+
+use crate::server::BCServer;
+
+pub const ONLINE_PLAYERS: &str = "bc_online_players";
+pub const CONNECTED_SOCKETS: &str = "bc_connected_sockets";
+pub const LOGIN_QUEUE_LENGTH: &str = "bc_login_queue_length";
+pub const LOGIN_QUEUE_WAIT: &str = "bc_login_queue_wait_seconds";
+pub const EVENTS: &str = "bc_events_total";
+pub const INVALID_PAYLOADS: &str = "bc_invalid_payloads_total";
+pub const EVENT_DURATION: &str = "bc_event_duration_seconds";
+pub const STORAGE_DURATION: &str = "bc_storage_operation_duration_seconds";
+pub const STORAGE_ERRORS: &str = "bc_storage_errors_total";
+pub const RATE_LIMIT_REJECTIONS: &str = "bc_rate_limit_rejections_total";
+pub const ACCOUNT_CREATIONS: &str = "bc_account_creations_total";
+pub const DELAYED_UPDATE_ACCOUNTS: &str = "bc_delayed_update_accounts";
+pub const DELAYED_UPDATE_BYTES: &str = "bc_delayed_update_bytes";
+
+/// From a fast in-memory handler to a slow database round trip
+const LATENCY_BUCKETS: &[f64] = &[
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+/// A queued login takes 50ms per login ahead of it
+const LOGIN_QUEUE_BUCKETS: &[f64] = &[0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+/// Installs the global recorder, metrics recorded before this are lost
+pub fn install_recorder() -> PrometheusHandle {
+    let handle = PrometheusBuilder::new()
+        .set_buckets(LATENCY_BUCKETS)
+        .and_then(|builder| {
+            builder.set_buckets_for_metric(
+                metrics_exporter_prometheus::Matcher::Full(LOGIN_QUEUE_WAIT.to_string()),
+                LOGIN_QUEUE_BUCKETS,
+            )
+        })
+        .and_then(PrometheusBuilder::install_recorder)
+        .expect("Failed to install the metrics recorder");
+
+    describe_gauge!(ONLINE_PLAYERS, "Logged in accounts");
+    describe_gauge!(CONNECTED_SOCKETS, "Open socket.io connections");
+    describe_gauge!(LOGIN_QUEUE_LENGTH, "Logins waiting to be processed");
+    describe_histogram!(
+        LOGIN_QUEUE_WAIT,
+        Unit::Seconds,
+        "Time from a login request to its processing"
+    );
+    describe_counter!(EVENTS, "Client events received, per event");
+    describe_counter!(
+        INVALID_PAYLOADS,
+        "Client events dropped because their payload couldn't be read"
+    );
+    describe_histogram!(
+        EVENT_DURATION,
+        Unit::Seconds,
+        "Time spent handling client events"
+    );
+    describe_histogram!(
+        STORAGE_DURATION,
+        Unit::Seconds,
+        "Account storage operations, per operation"
+    );
+    describe_counter!(STORAGE_ERRORS, "Failed account storage operations");
+    describe_counter!(RATE_LIMIT_REJECTIONS, "Requests refused by a rate limit");
+    describe_counter!(ACCOUNT_CREATIONS, "Accounts created");
+    describe_gauge!(
+        DELAYED_UPDATE_ACCOUNTS,
+        "Accounts with appearance, skill or game changes not written yet"
+    );
+    describe_gauge!(
+        DELAYED_UPDATE_BYTES,
+        Unit::Bytes,
+        "Size of the changes not written yet"
+    );
+    handle
+}
+
+async fn render(State((server, handle)): State<(Arc<BCServer>, PrometheusHandle)>) -> String {
+    // Gauges of the server state are read at scrape time
+    gauge!(ONLINE_PLAYERS).set(server.accounts.lock().await.len() as f64);
+    gauge!(CONNECTED_SOCKETS).set(server.io.sockets().len() as f64);
+    gauge!(LOGIN_QUEUE_LENGTH).set(server.login_queue.read().await.len() as f64);
+    let delayed = server.delayed_update_stats().await;
+    gauge!(DELAYED_UPDATE_ACCOUNTS).set(delayed.accounts as f64);
+    gauge!(DELAYED_UPDATE_BYTES).set(delayed.bytes as f64);
+
+    handle.run_upkeep();
+    handle.render()
+}
+
+/// Serves the metrics on `/metrics`
+pub fn router(server: Arc<BCServer>, handle: PrometheusHandle) -> Router {
+    Router::new()
+        .route("/metrics", get(render))
+        .with_state((server, handle))
+}
@@ -1,232 +1,251 @@
 use crate::{
     common::{
-        protocol::{
-            AccountBeepRequest, AccountCreateRequest, AccountLoginRequest, AccountQueryRequest,
-            AccountUpdateRequest,
-        },
-        types::{AccountCreationIP, LoginQueueStruct},
+        protocol::{ClientToServerEvent, WriteAck, WriteError, WriteOutcome},
+        types::{AccountCreationIP, LoginQueueStruct, PendingTwoFactor, TwoFactorFailures},
     },
+    config::{AppConfig, ConfigError},
+    mailer::{LogMailer, Mailer, SmtpMailer},
     models::account::Account,
+    monitoring::{EVENT_DURATION, EVENTS, INVALID_PAYLOADS},
+    storage::AccountStore,
+    utilities::{client_ip::ClientIpResolver, keyed_mutex::KeyedMutex},
 };
 use axum::extract::ConnectInfo;
-use dotenvy::dotenv;
-use figment::{Figment, providers::Env};
-use futures_util::stream::StreamExt;
-use mongodb::bson::{Document, doc};
-use mongodb::{Collection, Database};
+use metrics::{counter, histogram};
 use ordermap::{OrderMap, OrderSet};
-use serde::Deserialize;
+use serde_json::Value;
 use socketioxide::{
     SocketIo,
-    extract::{Data, HttpExtension, SocketRef},
+    extract::{AckSender, Event, SocketRef, TryData},
     socket::Sid,
 };
-use std::{net::SocketAddr, sync::Arc};
-use tokio::sync::{Mutex, RwLock};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, atomic::AtomicBool},
+    time::Instant,
+};
+use tokio::sync::{Mutex, Notify, RwLock};
+use tracing::{Instrument, Span, debug, field, info, info_span, warn};
 
 pub struct BCServer {
     pub config: AppConfig,
-    pub db: Database,
+    pub store: Arc<dyn AccountStore>,
     pub accounts: Mutex<Vec<Account>>,
-    pub next_member_number: RwLock<u32>,
     pub account_creation_ip: RwLock<Vec<AccountCreationIP>>,
     pub login_queue: RwLock<OrderMap<Sid, LoginQueueStruct>>,
     pub pending_logins: RwLock<OrderSet<Sid>>,
+    pub pending_two_factor: RwLock<HashMap<Sid, PendingTwoFactor>>,
+    /// Wrong two-factor codes per account name, to stop guessing over many sockets.
+    /// Locking an entry also keeps two-factor changes of that account in order.
+    pub two_factor_failures: Mutex<HashMap<String, Arc<Mutex<TwoFactorFailures>>>>,
+    /// Held per account name while taking its delayed updates and writing them, so the writes
+    /// of one account reach the database in the order they were taken
+    pub account_writes: KeyedMutex,
     pub io: SocketIo,
+    pub mailer: Box<dyn Mailer>,
+    pub client_ip_resolver: ClientIpResolver,
+    /// Set once a shutdown started, logins and creations are refused from then on
+    pub shutting_down: AtomicBool,
+    /// Set once the server is listening
+    pub startup_finished: AtomicBool,
+    pub started: Instant,
+    /// Wakes the server info broadcast when players come and go
+    pub server_info_changed: Notify,
 }
 
-#[derive(Debug, Deserialize)]
-pub struct AppConfig {
-    pub server_addr: String,
-    pub db_uri: String,
-    pub db_name: String,
-    pub db_accounts: String,
-    pub max_ip_account_per_day: u32,
-    pub max_ip_account_per_hour: u32,
-}
-
-pub fn load_config() -> AppConfig {
-    dotenv().ok();
-
-    Figment::from(Env::prefixed("APP_"))
-        .extract()
-        .expect("Failed to load configuration")
+/// Span of everything happening on the socket, with its sid, ip and, once logged in, account
+pub fn socket_span(socket: &SocketRef) -> Span {
+    socket.extensions.get::<Span>().unwrap_or_else(Span::none)
 }
 
 impl BCServer {
-    pub async fn new(db: Database, io: SocketIo) -> Arc<Self> {
-        let config = load_config();
-        match db.run_command(doc! { "ping": 1 }, None).await {
-            Ok(_) => {
-                println!("****************************************");
-                println!("Database: {} connected", config.db_name);
-                println!("****************************************");
-            }
-            Err(e) => {
-                eprintln!("❌ Failed to ping MongoDB: {e}");
-                panic!("Cannot continue without database connection");
-            }
-        }
-
-        // Find the highest MemberNumber
-        let accounts: Collection<Document> = db.collection(&config.db_accounts);
-        let filter = doc! { "MemberNumber": { "$exists": true, "$ne": null } };
-        let find_options = mongodb::options::FindOptions::builder()
-            .sort(doc! { "MemberNumber": -1 })
-            .limit(1)
-            .build();
-
-        let mut cursor = accounts.find(filter, find_options).await.unwrap();
-        let mut next_member_number = 1;
-
-        if let Some(Ok(doc)) = cursor.next().await {
-            if let Ok(member_num) = doc.get_i32("MemberNumber") {
-                next_member_number = (member_num + 1) as u32;
+    pub async fn new(
+        config: AppConfig,
+        store: Arc<dyn AccountStore>,
+        io: SocketIo,
+    ) -> Result<Arc<Self>, ConfigError> {
+        let mailer: Box<dyn Mailer> = match &config.smtp_url {
+            Some(url) => {
+                Box::new(SmtpMailer::new(url, &config.mail_from).map_err(ConfigError::Invalid)?)
             }
-        }
+            None => Box::new(LogMailer),
+        };
 
-        println!("Next Member Number: {next_member_number}");
+        let client_ip_resolver = config.client_ip_resolver();
 
         let server = Arc::new(Self {
-            db,
+            store,
             config,
             accounts: Mutex::new(<Vec<Account>>::new()),
-            next_member_number: RwLock::new(next_member_number),
             account_creation_ip: RwLock::new(<Vec<AccountCreationIP>>::new()),
             login_queue: RwLock::new(OrderMap::new()),
             pending_logins: RwLock::new(OrderSet::new()),
+            pending_two_factor: RwLock::new(HashMap::new()),
+            two_factor_failures: Mutex::new(HashMap::new()),
+            account_writes: KeyedMutex::default(),
             io,
+            mailer,
+            client_ip_resolver,
+            shutting_down: AtomicBool::new(false),
+            startup_finished: AtomicBool::new(false),
+            started: Instant::now(),
+            server_info_changed: Notify::new(),
         });
 
         server.clone().register_handlers();
+        server.clone().spawn_delayed_update_flush();
+        server.clone().spawn_server_info_broadcast();
 
-        server
+        Ok(server)
     }
 
     pub fn register_handlers(self: Arc<Self>) {
         let io = self.io.clone();
-        io.ns(
-            "/",
-            move |socket: SocketRef, client_ip: HttpExtension<ConnectInfo<SocketAddr>>| {
-                self.on_connect(socket, client_ip);
-            },
-        );
+        io.ns("/", move |socket: SocketRef| {
+            self.on_connect(socket);
+        });
     }
 
-    fn on_connect(
-        self: Arc<Self>,
-        socket: SocketRef,
-        client_ip: HttpExtension<ConnectInfo<SocketAddr>>,
-    ) {
-        let ip = client_ip.ip();
-        let port = client_ip.port();
-        let _socket_id = socket.id;
+    /// Address of the player behind the socket, resolved through the trusted proxies
+    pub fn client_ip(&self, socket: &SocketRef) -> Option<IpAddr> {
+        let parts = socket.req_parts();
+        let peer = parts.extensions.get::<ConnectInfo<SocketAddr>>()?.ip();
+        Some(self.client_ip_resolver.resolve(peer, &parts.headers))
+    }
 
-        println!("Connected: {}, {ip}:{port}", socket.id);
-        let id = socket.id;
-        socket.on_disconnect(move || {
-            println!("Disconnected: {id}, {ip}:{port}");
-        });
+    /// Name of the account logged in with the given session ID
+    pub async fn online_account_name(&self, session_id: &str) -> Option<String> {
+        let accounts = self.accounts.lock().await;
+        accounts
+            .iter()
+            .find(|a| a.id.as_deref() == Some(session_id))
+            .map(|a| a.account_name.clone())
+    }
 
-        let server = self.clone();
-        socket.on(
-            "AccountCreate",
-            move |socket: SocketRef, Data(data): Data<serde_json::Value>, client_ip| {
-                let server = server.clone();
-                Box::pin(async move {
-                    let data_clone = data.clone();
-                    match serde_json::from_value::<AccountCreateRequest>(data) {
-                        Ok(req) => {
-                            server.on_account_create(socket, req, client_ip).await;
-                        }
-                        Err(err) => {
-                            println!("AccountCreate: Invalid payload: {err} | Raw: {data_clone}");
-                            let _ = socket.emit("CreationResponse", "Invalid request data");
-                        }
-                    }
-                })
-            },
+    fn on_connect(self: Arc<Self>, socket: SocketRef) {
+        let ip = self
+            .client_ip(&socket)
+            .map(|ip| ip.to_string())
+            .unwrap_or_default();
+
+        // The account fields are filled in once the socket logs in
+        let span = info_span!(
+            "socket",
+            sid = %socket.id,
+            %ip,
+            account = field::Empty,
+            member_number = field::Empty,
         );
+        socket.extensions.insert(span.clone());
+        span.in_scope(|| info!("connected"));
 
+        let id = socket.id;
         let server = self.clone();
-        socket.on(
-            "AccountLogin",
-            move |socket: SocketRef, Data(data): Data<serde_json::Value>| {
-                let server = server.clone();
-                Box::pin(async move {
-                    let data_clone = data.clone();
-                    match serde_json::from_value::<AccountLoginRequest>(data) {
-                        Ok(req) => {
-                            server.on_account_login(socket, req).await;
-                        }
-                        Err(err) => {
-                            println!("AccounLogin: Invalid payload: {err} | Raw: {data_clone}");
-                            let _ = socket.emit("LoginResponse", "Invalid request data");
-                        }
-                    }
-                })
-            },
-        );
+        socket.on_disconnect(move || {
+            async move {
+                info!("disconnected");
+                server.pending_two_factor.write().await.remove(&id);
+                server.remove_online_account(id).await;
+            }
+            .instrument(span)
+        });
 
         let server = self.clone();
-        socket.on(
-            "AccountUpdate",
-            move |socket: SocketRef, Data(data): Data<serde_json::Value>| {
+        socket.on_fallback(
+            move |socket: SocketRef,
+                  Event(event): Event,
+                  TryData(data): TryData<Value>,
+                  ack: AckSender| {
                 let server = server.clone();
-                Box::pin(async move {
-                    let data_clone = data.clone();
-                    match serde_json::from_value::<AccountUpdateRequest>(data) {
-                        Ok(req) => {
-                            println!("AccountUpdate: received {req:?}");
-                            server.on_account_update(socket, req).await;
-                        }
-                        Err(err) => {
-                            println!("AccountUpdate: Invalid payload: {err} | Raw: {data_clone}");
-                            let _ = socket.emit("LoginResponse", "Invalid request data");
-                        }
-                    }
-                })
+                // Events sent without a payload read as null
+                async move {
+                    server
+                        .on_event(socket, event, data.unwrap_or_default(), ack)
+                        .await
+                }
             },
         );
+    }
 
-        let server = self.clone();
-        socket.on(
-            "AccountBeep",
-            move |socket: SocketRef, Data(data): Data<serde_json::Value>| {
-                let server = server.clone();
-                Box::pin(async move {
-                    let data_clone = data.clone();
-                    match serde_json::from_value::<AccountBeepRequest>(data) {
-                        Ok(req) => {
-                            server.on_account_beep(socket, req).await;
-                        }
-                        Err(err) => {
-                            println!("AccountBeep: Invalid payload: {err} | Raw: {data_clone}");
-                            let _ = socket.emit("LoginResponse", "Invalid request data");
-                        }
+    /// Single entry point of every client event: reads the payload, answers the event the client
+    /// waits on when it can't, and hands it to its handler otherwise. Writes also answer the ack
+    /// callback, if the client sent one.
+    async fn on_event(&self, socket: SocketRef, event: String, data: Value, ack: AckSender) {
+        let span = info_span!(parent: &socket_span(&socket), "event", event = %event);
+        async {
+            let Some(parsed) = ClientToServerEvent::parse(&event, data) else {
+                debug!("unhandled event");
+                return;
+            };
+            // Only known events become labels, clients can send any name
+            counter!(EVENTS, "event" => event.clone()).increment(1);
+            let request = match parsed {
+                Ok(request) => request,
+                Err(invalid) => {
+                    // The error message can quote the payload
+                    warn!(error = ?invalid.error.classify(), "invalid payload");
+                    counter!(INVALID_PAYLOADS, "event" => event.clone()).increment(1);
+                    if let Some((response, payload)) =
+                        ClientToServerEvent::invalid_payload_response(&event)
+                    {
+                        let _ = socket.emit(response, &payload);
                     }
-                })
-            },
-        );
-
-        let server = self.clone();
-        socket.on(
-            "AccountQuery",
-            move |socket: SocketRef, Data(data): Data<serde_json::Value>| {
-                let server = server.clone();
-                Box::pin(async move {
-                    let data_clone = data.clone();
-                    match serde_json::from_value::<AccountQueryRequest>(data) {
-                        Ok(req) => {
-                            server.on_account_query(socket, req).await;
-                        }
-                        Err(err) => {
-                            println!("AccountQuery: Invalid payload: {err} | Raw: {data_clone}");
-                            let _ = socket.emit("LoginResponse", "Invalid request data");
-                        }
+                    if invalid.is_write {
+                        let _ = ack.send(&WriteAck::from(Err(WriteError::InvalidPayload)));
                     }
-                })
-            },
-        );
+                    return;
+                }
+            };
+            let started = Instant::now();
+            let result = self.dispatch(socket, request).await;
+            let elapsed = started.elapsed();
+            histogram!(EVENT_DURATION, "event" => event.clone()).record(elapsed.as_secs_f64());
+            debug!(elapsed_ms = elapsed.as_millis() as u64, "handled");
+            if let Some(result) = result {
+                if let Err(err) = &result {
+                    debug!("write failed: {err:?}");
+                }
+                let _ = ack.send(&WriteAck::from(result));
+            }
+        }
+        .instrument(span)
+        .await
+    }
+
+    /// Runs the handler of the event, writes return their outcome for the ack callback
+    async fn dispatch(
+        &self,
+        socket: SocketRef,
+        event: ClientToServerEvent,
+    ) -> Option<Result<WriteOutcome, WriteError>> {
+        match event {
+            ClientToServerEvent::AccountCreate(req) => self.on_account_create(socket, req).await,
+            ClientToServerEvent::AccountLogin(req) => self.on_account_login(socket, req).await,
+            ClientToServerEvent::AccountLoginTotp(req) => {
+                self.on_account_login_totp(socket, req).await
+            }
+            ClientToServerEvent::AccountBeep(req) => self.on_account_beep(socket, req).await,
+            ClientToServerEvent::AccountQuery(req) => self.on_account_query(socket, req).await,
+            ClientToServerEvent::AccountUpdate(req) => {
+                return Some(self.on_account_update(socket, *req).await);
+            }
+            ClientToServerEvent::AccountUpdateEmail(req) => {
+                return Some(self.on_account_update_email(socket, req).await);
+            }
+            ClientToServerEvent::AccountVerifyEmail(req) => {
+                return Some(self.on_account_verify_email(socket, req).await);
+            }
+            ClientToServerEvent::AccountTwoFactorSetup => {
+                return Some(self.on_account_two_factor_setup(socket).await);
+            }
+            ClientToServerEvent::AccountTwoFactorConfirm(req) => {
+                return Some(self.on_account_two_factor_confirm(socket, req).await);
+            }
+            ClientToServerEvent::AccountTwoFactorDisable(req) => {
+                return Some(self.on_account_two_factor_disable(socket, req).await);
+            }
+        }
+        None
     }
 }
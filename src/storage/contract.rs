@@ -0,0 +1,196 @@
+// Behaviour every `AccountStore` backend has to share. Each backend runs the whole list
+// through `store_contract_tests!` in its own tests.
+
+use serde_json::json;
+
+use crate::{
+    models::account::Account,
+    storage::{AccountPatch, AccountStore, StoreError},
+};
+
+fn account(account_name: &str, member_number: u32, email: Option<&str>) -> Account {
+    Account {
+        account_name: account_name.to_string(),
+        name: account_name.to_lowercase(),
+        member_number,
+        email: email.map(str::to_string),
+        money: 100,
+        ..Default::default()
+    }
+}
+
+fn patch(fields: serde_json::Value) -> AccountPatch {
+    fields.as_object().cloned().unwrap()
+}
+
+pub async fn insert_then_find(store: &dyn AccountStore) {
+    let mut alice = account("ALICE", 1, Some("alice@example.com"));
+    alice.id = Some("session".to_string());
+    store.insert(&alice).await.unwrap();
+
+    let found = store.find_by_name("ALICE").await.unwrap().unwrap();
+    assert_eq!(found.member_number, 1);
+    assert_eq!(found.money, 100);
+    assert_eq!(found.id, None, "the session ID is never stored");
+
+    let found = store.find_by_member_number(1).await.unwrap().unwrap();
+    assert_eq!(found.account_name, "ALICE");
+    let found = store.find_by_email(" Alice@Example.COM ").await.unwrap();
+    assert_eq!(found.map(|a| a.account_name).as_deref(), Some("ALICE"));
+
+    assert!(store.find_by_name("BOB").await.unwrap().is_none());
+    assert!(store.find_by_member_number(2).await.unwrap().is_none());
+    assert!(
+        store
+            .find_by_email("bob@example.com")
+            .await
+            .unwrap()
+            .is_none()
+    );
+}
+
+pub async fn insert_rejects_duplicates(store: &dyn AccountStore) {
+    store
+        .insert(&account("ALICE", 1, Some("alice@example.com")))
+        .await
+        .unwrap();
+
+    for duplicate in [
+        account("ALICE", 2, None),
+        account("BOB", 1, None),
+        account("BOB", 2, Some("alice@example.com")),
+    ] {
+        let result = store.insert(&duplicate).await;
+        assert!(
+            matches!(result, Err(StoreError::Duplicate(_))),
+            "{duplicate:?} gave {result:?}"
+        );
+    }
+    // Accounts without an email don't collide with each other
+    store.insert(&account("BOB", 2, None)).await.unwrap();
+    store.insert(&account("CAROL", 3, None)).await.unwrap();
+}
+
+pub async fn update_overwrites_given_fields(store: &dyn AccountStore) {
+    let mut alice = account("ALICE", 1, Some("alice@example.com"));
+    alice.description = Some("hello".to_string());
+    store.insert(&alice).await.unwrap();
+
+    store
+        .update(
+            "ALICE",
+            patch(json!({ "Money": 250, "Description": null, "Title": "Mistress" })),
+        )
+        .await
+        .unwrap();
+
+    let found = store.find_by_name("ALICE").await.unwrap().unwrap();
+    assert_eq!(found.money, 250);
+    assert_eq!(found.description, None, "null clears an optional field");
+    assert_eq!(found.title.as_deref(), Some("Mistress"));
+    assert_eq!(found.email.as_deref(), Some("alice@example.com"));
+    assert_eq!(found.member_number, 1);
+}
+
+pub async fn update_rejects_a_taken_email(store: &dyn AccountStore) {
+    store
+        .insert(&account("ALICE", 1, Some("alice@example.com")))
+        .await
+        .unwrap();
+    store
+        .insert(&account("BOB", 2, Some("bob@example.com")))
+        .await
+        .unwrap();
+
+    let result = store
+        .update("BOB", patch(json!({ "Email": "alice@example.com" })))
+        .await;
+    assert!(
+        matches!(result, Err(StoreError::Duplicate(_))),
+        "{result:?}"
+    );
+    let bob = store.find_by_name("BOB").await.unwrap().unwrap();
+    assert_eq!(bob.email.as_deref(), Some("bob@example.com"));
+
+    // Setting an account's own email again is fine
+    store
+        .update("ALICE", patch(json!({ "Email": "alice@example.com" })))
+        .await
+        .unwrap();
+}
+
+pub async fn update_of_a_missing_account_fails(store: &dyn AccountStore) {
+    let result = store.update("NOBODY", patch(json!({ "Money": 1 }))).await;
+    assert!(
+        matches!(&result, Err(StoreError::NotFound(name)) if name == "NOBODY"),
+        "{result:?}"
+    );
+    assert!(store.find_by_name("NOBODY").await.unwrap().is_none());
+}
+
+pub async fn member_numbers_are_never_reused(store: &dyn AccountStore) {
+    store.insert(&account("ALICE", 41, None)).await.unwrap();
+
+    let first = store.next_member_number().await.unwrap();
+    assert!(first > 41, "{first} is taken");
+    let second = store.next_member_number().await.unwrap();
+    assert!(second > first, "{second} was handed out before");
+}
+
+pub async fn list_page_walks_accounts_in_name_order(store: &dyn AccountStore) {
+    for (index, name) in ["DAVE", "ALICE", "CAROL", "BOB", "EVE"].iter().enumerate() {
+        store
+            .insert(&account(name, index as u32 + 1, None))
+            .await
+            .unwrap();
+    }
+
+    let mut names = vec![];
+    let mut after = None;
+    loop {
+        let page = store.list_page(after.clone(), 2).await.unwrap();
+        assert!(page.accounts.len() <= 2);
+        assert!(page.unreadable.is_empty());
+        if page.last.is_none() {
+            break;
+        }
+        assert_eq!(
+            page.last,
+            page.accounts.last().map(|a| a.account_name.clone())
+        );
+        after = page.last;
+        names.extend(page.accounts.into_iter().map(|a| a.account_name));
+    }
+    assert_eq!(names, ["ALICE", "BOB", "CAROL", "DAVE", "EVE"]);
+}
+
+/// Runs every contract check against a fresh store from `$new_store` each
+macro_rules! store_contract_tests {
+    ($new_store:expr) => {
+        mod contract {
+            use super::*;
+
+            store_contract_tests!(
+                @tests $new_store;
+                insert_then_find,
+                insert_rejects_duplicates,
+                update_overwrites_given_fields,
+                update_rejects_a_taken_email,
+                update_of_a_missing_account_fails,
+                member_numbers_are_never_reused,
+                list_page_walks_accounts_in_name_order,
+            );
+        }
+    };
+    (@tests $new_store:expr; $($name:ident,)*) => {
+        $(
+            #[tokio::test]
+            async fn $name() {
+                let store = $new_store;
+                crate::storage::contract::$name(&store).await;
+            }
+        )*
+    };
+}
+
+pub(crate) use store_contract_tests;
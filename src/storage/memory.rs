@@ -0,0 +1,182 @@
+use async_trait::async_trait;
+use serde_json::Value;
+use std::collections::HashMap;
+use tokio::sync::{Mutex, RwLock};
+
+use crate::{
+    models::{account::Account, account_migrations::MigrationReport},
+    storage::{AccountPage, AccountPatch, AccountStore, StoreError},
+};
+
+/// Keeps accounts in a map, keyed by account name
+#[derive(Default)]
+pub struct MemoryAccountStore {
+    accounts: RwLock<HashMap<String, Account>>,
+    last_member_number: Mutex<u32>,
+}
+
+fn has_email(account: &Account, email: &str) -> bool {
+    let email = Account::normalize_mail(email);
+    !email.is_empty()
+        && account
+            .email
+            .as_deref()
+            .is_some_and(|stored| Account::normalize_mail(stored) == email)
+}
+
+impl MemoryAccountStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl AccountStore for MemoryAccountStore {
+    async fn ping(&self) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    async fn find_by_name(&self, account_name: &str) -> Result<Option<Account>, StoreError> {
+        Ok(self.accounts.read().await.get(account_name).cloned())
+    }
+
+    async fn find_by_member_number(
+        &self,
+        member_number: u32,
+    ) -> Result<Option<Account>, StoreError> {
+        let accounts = self.accounts.read().await;
+        Ok(accounts
+            .values()
+            .find(|a| a.member_number == member_number)
+            .cloned())
+    }
+
+    async fn find_by_email(&self, email: &str) -> Result<Option<Account>, StoreError> {
+        let accounts = self.accounts.read().await;
+        Ok(accounts.values().find(|a| has_email(a, email)).cloned())
+    }
+
+    async fn insert(&self, account: &Account) -> Result<(), StoreError> {
+        let mut accounts = self.accounts.write().await;
+        if accounts.contains_key(&account.account_name) {
+            return Err(StoreError::Duplicate("AccountName".to_string()));
+        }
+        if accounts
+            .values()
+            .any(|a| a.member_number == account.member_number)
+        {
+            return Err(StoreError::Duplicate("MemberNumber".to_string()));
+        }
+        if let Some(email) = &account.email
+            && accounts.values().any(|a| has_email(a, email))
+        {
+            return Err(StoreError::Duplicate("Email".to_string()));
+        }
+
+        let mut account = account.clone();
+        // Only the stored document is kept, never the live session
+        account.id = None;
+        account.socket = None;
+        accounts.insert(account.account_name.clone(), account);
+        Ok(())
+    }
+
+    async fn update(&self, account_name: &str, patch: AccountPatch) -> Result<(), StoreError> {
+        let mut accounts = self.accounts.write().await;
+        if let Some(Value::String(email)) = patch.get("Email")
+            && accounts
+                .values()
+                .any(|a| a.account_name != account_name && has_email(a, email))
+        {
+            return Err(StoreError::Duplicate("Email".to_string()));
+        }
+        let Some(account) = accounts.get_mut(account_name) else {
+            return Err(StoreError::NotFound(account_name.to_string()));
+        };
+
+        // Patches use the serialized field names, so apply them on the serialized account
+        let mut value =
+            serde_json::to_value(&*account).map_err(|e| StoreError::Backend(e.to_string()))?;
+        let Some(fields) = value.as_object_mut() else {
+            return Err(StoreError::Backend("account is not an object".to_string()));
+        };
+        fields.extend(patch);
+        *account = serde_json::from_value(value).map_err(|e| StoreError::Backend(e.to_string()))?;
+        Ok(())
+    }
+
+    async fn next_member_number(&self) -> Result<u32, StoreError> {
+        let mut last = self.last_member_number.lock().await;
+        let max = self
+            .accounts
+            .read()
+            .await
+            .values()
+            .map(|a| a.member_number)
+            .max()
+            .unwrap_or(0);
+        *last = (*last).max(max) + 1;
+        Ok(*last)
+    }
+
+    async fn list_page(
+        &self,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<AccountPage, StoreError> {
+        let accounts = self.accounts.read().await;
+        let after = after.unwrap_or_default();
+        let mut page: Vec<Account> = accounts
+            .values()
+            .filter(|a| a.account_name > after)
+            .cloned()
+            .collect();
+        page.sort_by(|a, b| a.account_name.cmp(&b.account_name));
+        page.truncate(limit);
+        Ok(AccountPage {
+            last: page.last().map(|a| a.account_name.clone()),
+            accounts: page,
+            unreadable: vec![],
+        })
+    }
+
+    async fn migrate_accounts(&self, dry_run: bool) -> Result<MigrationReport, StoreError> {
+        // Accounts only ever get here through the current model
+        Ok(MigrationReport {
+            dry_run,
+            scanned: self.accounts.read().await.len(),
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::contract::store_contract_tests;
+
+    store_contract_tests!(MemoryAccountStore::new());
+
+    #[tokio::test]
+    async fn find_by_email_ignores_case_and_spaces() {
+        let store = MemoryAccountStore::new();
+        let account = Account {
+            account_name: "ALICE".to_string(),
+            member_number: 1,
+            email: Some(" Alice@Example.com".to_string()),
+            ..Default::default()
+        };
+        store.insert(&account).await.unwrap();
+
+        let found = store.find_by_email("alice@example.com").await.unwrap();
+        assert_eq!(found.map(|a| a.account_name).as_deref(), Some("ALICE"));
+        assert!(
+            store
+                .find_by_email("bob@example.com")
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert!(store.find_by_email("").await.unwrap().is_none());
+    }
+}
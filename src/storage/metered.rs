@@ -0,0 +1,89 @@
+use async_trait::async_trait;
+use metrics::{counter, histogram};
+use std::{sync::Arc, time::Instant};
+
+use crate::{
+    models::{account::Account, account_migrations::MigrationReport},
+    monitoring::{STORAGE_DURATION, STORAGE_ERRORS},
+    storage::{AccountPage, AccountPatch, AccountStore, StoreError},
+};
+
+/// Records the latency and failures of every operation of the wrapped store
+pub struct MeteredAccountStore {
+    inner: Arc<dyn AccountStore>,
+}
+
+impl MeteredAccountStore {
+    pub fn new(inner: Arc<dyn AccountStore>) -> Self {
+        Self { inner }
+    }
+}
+
+async fn timed<T>(
+    operation: &'static str,
+    call: impl Future<Output = Result<T, StoreError>>,
+) -> Result<T, StoreError> {
+    let started = Instant::now();
+    let result = call.await;
+    histogram!(STORAGE_DURATION, "operation" => operation).record(started.elapsed().as_secs_f64());
+    if let Err(e) = &result {
+        // Duplicates are expected now and then, a name taken meanwhile by another player
+        let kind = match e {
+            StoreError::Duplicate(_) => "duplicate",
+            StoreError::NotFound(_) => "not_found",
+            StoreError::Backend(_) => "backend",
+        };
+        counter!(STORAGE_ERRORS, "operation" => operation, "kind" => kind).increment(1);
+    }
+    result
+}
+
+#[async_trait]
+impl AccountStore for MeteredAccountStore {
+    async fn ping(&self) -> Result<(), StoreError> {
+        timed("ping", self.inner.ping()).await
+    }
+
+    async fn find_by_name(&self, account_name: &str) -> Result<Option<Account>, StoreError> {
+        timed("find_by_name", self.inner.find_by_name(account_name)).await
+    }
+
+    async fn find_by_member_number(
+        &self,
+        member_number: u32,
+    ) -> Result<Option<Account>, StoreError> {
+        timed(
+            "find_by_member_number",
+            self.inner.find_by_member_number(member_number),
+        )
+        .await
+    }
+
+    async fn find_by_email(&self, email: &str) -> Result<Option<Account>, StoreError> {
+        timed("find_by_email", self.inner.find_by_email(email)).await
+    }
+
+    async fn insert(&self, account: &Account) -> Result<(), StoreError> {
+        timed("insert", self.inner.insert(account)).await
+    }
+
+    async fn update(&self, account_name: &str, patch: AccountPatch) -> Result<(), StoreError> {
+        timed("update", self.inner.update(account_name, patch)).await
+    }
+
+    async fn next_member_number(&self) -> Result<u32, StoreError> {
+        timed("next_member_number", self.inner.next_member_number()).await
+    }
+
+    async fn list_page(
+        &self,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<AccountPage, StoreError> {
+        timed("list_page", self.inner.list_page(after, limit)).await
+    }
+
+    async fn migrate_accounts(&self, dry_run: bool) -> Result<MigrationReport, StoreError> {
+        timed("migrate_accounts", self.inner.migrate_accounts(dry_run)).await
+    }
+}
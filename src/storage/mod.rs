@@ -0,0 +1,162 @@
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::{fmt, sync::Arc};
+use tracing::{info, warn};
+
+use crate::{
+    config::AppConfig,
+    models::{account::Account, account_migrations::MigrationReport},
+    storage::{memory::MemoryAccountStore, mongo::MongoAccountStore},
+};
+
+#[cfg(test)]
+mod contract;
+pub mod memory;
+pub mod metered;
+pub mod mongo;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+/// Fields to overwrite on an account, keyed by their serialized (PascalCase) name.
+/// A `null` value clears an optional field.
+pub type AccountPatch = Map<String, Value>;
+
+/// One page of `AccountStore::list_page`
+#[derive(Debug, Default)]
+pub struct AccountPage {
+    pub accounts: Vec<Account>,
+    /// Names of the accounts on the page that couldn't be read
+    pub unreadable: Vec<String>,
+    /// Name of the last account on the page, readable or not, the next page starts after it.
+    /// `None` once there's nothing left.
+    pub last: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum StoreError {
+    /// A unique field (account name, member number or email) is already taken
+    Duplicate(String),
+    /// No account has the given account name
+    NotFound(String),
+    Backend(String),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Duplicate(e) => write!(f, "duplicate key: {e}"),
+            StoreError::NotFound(name) => write!(f, "no account named {name}"),
+            StoreError::Backend(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StorageBackend {
+    #[default]
+    MongoDb,
+    /// Nothing survives a restart, for development and tests
+    Memory,
+    /// Single file database, needs the `sqlite` feature
+    #[cfg(feature = "sqlite")]
+    Sqlite,
+}
+
+#[async_trait]
+pub trait AccountStore: Send + Sync {
+    /// Checks that the backend is reachable
+    async fn ping(&self) -> Result<(), StoreError>;
+
+    async fn find_by_name(&self, account_name: &str) -> Result<Option<Account>, StoreError>;
+
+    async fn find_by_member_number(
+        &self,
+        member_number: u32,
+    ) -> Result<Option<Account>, StoreError>;
+
+    /// Looks up the normalized email. Stored emails are only compared as they are, accounts
+    /// saved before the email migration need `migrate-accounts` to be found.
+    async fn find_by_email(&self, email: &str) -> Result<Option<Account>, StoreError>;
+
+    async fn insert(&self, account: &Account) -> Result<(), StoreError>;
+
+    /// Overwrites the given fields, fails with `NotFound` if the account doesn't exist
+    async fn update(&self, account_name: &str, patch: AccountPatch) -> Result<(), StoreError>;
+
+    /// Hands out a member number nobody got before, even with several servers on one database
+    async fn next_member_number(&self) -> Result<u32, StoreError>;
+
+    /// Up to `limit` accounts sorted by account name, starting after `after`. Accounts that
+    /// can't be read count towards the limit and are listed in `unreadable`.
+    async fn list_page(
+        &self,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<AccountPage, StoreError>;
+
+    /// Upgrades every stored account to the current schema version, accounts are upgraded
+    /// in memory anyway when loaded
+    async fn migrate_accounts(&self, dry_run: bool) -> Result<MigrationReport, StoreError>;
+}
+
+/// Connects to the configured backend and makes sure it's usable. Status goes to the logs, on
+/// stderr, so admin commands can write their output to stdout.
+///
+/// For `serving`, the configured startup migration runs first, then the MongoDB indexes are
+/// built on the migrated accounts and have to be right. Admin commands only get a warning about
+/// the indexes, so the accounts keeping them from being built can still be looked into.
+pub async fn open_store(
+    config: &AppConfig,
+    serving: bool,
+) -> Result<Arc<dyn AccountStore>, StoreError> {
+    let migrate = serving && config.migrate_accounts_on_startup;
+    let store: Arc<dyn AccountStore> = match config.storage {
+        StorageBackend::MongoDb => {
+            let store = MongoAccountStore::connect(config).await?;
+            info!(db_name = %config.db_name, "database connected");
+            if migrate {
+                migrate_on_startup(&store).await?;
+            }
+            match store.ensure_indexes().await {
+                Err(e) if !serving => warn!("{e}"),
+                result => result?,
+            }
+            store.ensure_member_number_counter().await?;
+            return Ok(Arc::new(store));
+        }
+        StorageBackend::Memory => {
+            warn!("using in-memory storage, accounts are lost on restart");
+            Arc::new(MemoryAccountStore::new())
+        }
+        #[cfg(feature = "sqlite")]
+        StorageBackend::Sqlite => {
+            let store = sqlite::SqliteAccountStore::open(&config.sqlite_path)?;
+            info!(sqlite_path = %config.sqlite_path, "database opened");
+            Arc::new(store)
+        }
+    };
+    if migrate {
+        migrate_on_startup(store.as_ref()).await?;
+    }
+    Ok(store)
+}
+
+async fn migrate_on_startup(store: &dyn AccountStore) -> Result<(), StoreError> {
+    let report = store.migrate_accounts(false).await?;
+    info!("{report}");
+    if !report.duplicate_emails.is_empty() {
+        warn!(
+            accounts = report
+                .duplicate_emails
+                .values()
+                .map(Vec::len)
+                .sum::<usize>(),
+            "some accounts share an email and were left out of the migration, \
+             see `migrate-accounts --dry-run`"
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,433 @@
+use async_trait::async_trait;
+use mongodb::{
+    Client, Collection, Database, IndexModel,
+    bson::{self, Bson, Document, doc},
+    error::{ErrorKind, WriteFailure},
+    options::{
+        ClientOptions, FindOneAndUpdateOptions, FindOneOptions, FindOptions, IndexOptions,
+        ReturnDocument, UpdateOptions,
+    },
+};
+
+use serde_json::{Map, Value};
+use tracing::{error, info, warn};
+
+use crate::{
+    config::AppConfig,
+    models::{
+        account::Account,
+        account_migrations::{CURRENT_SCHEMA_VERSION, MigrationReport, migrate_account},
+    },
+    storage::{AccountPage, AccountPatch, AccountStore, StoreError},
+};
+
+pub struct MongoAccountStore {
+    db: Database,
+    accounts: Collection<Account>,
+    counters: Collection<Document>,
+}
+
+/// Counter document holding the last member number handed out
+const MEMBER_NUMBER_COUNTER: &str = "MemberNumber";
+
+impl From<mongodb::error::Error> for StoreError {
+    fn from(err: mongodb::error::Error) -> Self {
+        let duplicate = match err.kind.as_ref() {
+            ErrorKind::Write(WriteFailure::WriteError(e)) => e.code == 11000,
+            ErrorKind::Command(e) => e.code == 11000,
+            _ => false,
+        };
+        if duplicate {
+            StoreError::Duplicate(err.to_string())
+        } else {
+            StoreError::Backend(err.to_string())
+        }
+    }
+}
+
+/// Reads a number whatever width it was stored with
+pub fn bson_as_u32(value: &Bson) -> Option<u32> {
+    match value {
+        Bson::Int32(n) => u32::try_from(*n).ok(),
+        Bson::Int64(n) => u32::try_from(*n).ok(),
+        Bson::Double(n) if n.fract() == 0.0 && *n >= 0.0 => Some(*n as u32),
+        _ => None,
+    }
+}
+
+fn backend_error(err: impl ToString) -> StoreError {
+    StoreError::Backend(err.to_string())
+}
+
+/// Reads a stored account, upgrading older documents on the way
+fn account_from_document(doc: Document) -> Result<Account, StoreError> {
+    let version = doc.get("SchemaVersion").and_then(bson_as_u32).unwrap_or(0);
+    if version >= CURRENT_SCHEMA_VERSION {
+        return bson::from_document(doc).map_err(backend_error);
+    }
+    let mut fields: Map<String, Value> = bson::from_document(doc).map_err(backend_error)?;
+    migrate_account(&mut fields);
+    serde_json::from_value(Value::Object(fields)).map_err(backend_error)
+}
+
+impl MongoAccountStore {
+    pub fn new(db: Database, accounts_collection: &str, counters_collection: &str) -> Self {
+        let accounts = db.collection(accounts_collection);
+        let counters = db.collection(counters_collection);
+        Self {
+            db,
+            accounts,
+            counters,
+        }
+    }
+
+    /// Opens the configured database and checks that it answers
+    pub async fn connect(config: &AppConfig) -> Result<Self, StoreError> {
+        let options = ClientOptions::parse(&config.db_uri).await?;
+        let client = Client::with_options(options)?;
+        let store = Self::new(
+            client.database(&config.db_name),
+            &config.db_accounts,
+            &config.db_counters,
+        );
+        store.ping().await?;
+        Ok(store)
+    }
+
+    /// The accounts collection without the typed model, for documents in older shapes
+    fn documents(&self) -> Collection<Document> {
+        self.accounts.clone_with_type()
+    }
+
+    async fn find_account(&self, filter: Document) -> Result<Option<Account>, StoreError> {
+        self.documents()
+            .find_one(filter, None)
+            .await?
+            .map(account_from_document)
+            .transpose()
+    }
+
+    /// Moves the member number counter past every existing account, for databases that were
+    /// filled before the counter existed
+    pub async fn ensure_member_number_counter(&self) -> Result<(), StoreError> {
+        let options = FindOneOptions::builder()
+            .sort(doc! { "MemberNumber": -1 })
+            .projection(doc! { "MemberNumber": 1 })
+            .build();
+        let max = self
+            .documents()
+            .find_one(
+                doc! { "MemberNumber": { "$exists": true, "$ne": null } },
+                options,
+            )
+            .await?
+            .as_ref()
+            .and_then(|d| d.get("MemberNumber"))
+            .and_then(bson_as_u32)
+            .unwrap_or(0);
+        // Two servers creating the counter at once makes one upsert fail, the retry finds it
+        let mut attempts = 0;
+        loop {
+            let result = self
+                .counters
+                .update_one(
+                    doc! { "_id": MEMBER_NUMBER_COUNTER },
+                    doc! { "$max": { "Value": i64::from(max) } },
+                    UpdateOptions::builder().upsert(true).build(),
+                )
+                .await
+                .map_err(StoreError::from);
+            match result {
+                Err(StoreError::Duplicate(_)) if attempts < 3 => attempts += 1,
+                result => return result.map(|_| ()),
+            }
+        }
+    }
+
+    /// Creates the indexes the queries rely on. An existing index on the same keys with
+    /// other options is reported instead of being replaced, so fixing it stays a manual step
+    pub async fn ensure_indexes(&self) -> Result<(), StoreError> {
+        let collection = self.accounts.name().to_string();
+        let mut existing = Vec::new();
+        let mut cursor = self.accounts.list_indexes(None).await?;
+        while cursor.advance().await? {
+            existing.push(cursor.deserialize_current()?);
+        }
+
+        let mut drift = Vec::new();
+        for index in required_account_indexes() {
+            match existing.iter().find(|e| e.keys == index.keys) {
+                Some(current) if index_options_match(current, &index) => {}
+                Some(current) => drift.push(format!(
+                    "{} has options {}, expected {}",
+                    index.keys,
+                    describe_index_options(current),
+                    describe_index_options(&index)
+                )),
+                None => {
+                    info!(index = %index.keys, %collection, "creating index");
+                    let keys = index.keys.clone();
+                    self.accounts.create_index(index, None).await.map_err(|e| {
+                        let hint = match StoreError::from(e.clone()) {
+                            StoreError::Duplicate(_) => {
+                                ", `migrate-accounts --dry-run` lists accounts sharing an email"
+                            }
+                            _ => "",
+                        };
+                        backend_error(format!(
+                            "failed to create index {keys} on {collection}: {e}{hint}"
+                        ))
+                    })?;
+                }
+            }
+        }
+
+        let required = required_account_indexes();
+        for index in &existing {
+            if index.keys != doc! { "_id": 1 } && !required.iter().any(|r| r.keys == index.keys) {
+                warn!(index = %index.keys, %collection, "index isn't used by the server");
+            }
+        }
+
+        if drift.is_empty() {
+            Ok(())
+        } else {
+            Err(backend_error(format!(
+                "indexes on {collection} differ from what the server needs: {}",
+                drift.join("; ")
+            )))
+        }
+    }
+}
+
+/// Indexes backing the account lookups, there is no collection with expiring documents yet
+fn required_account_indexes() -> Vec<IndexModel> {
+    let unique = |keys: Document| {
+        IndexModel::builder()
+            .keys(keys)
+            .options(IndexOptions::builder().unique(true).build())
+            .build()
+    };
+    vec![
+        unique(doc! { "AccountName": 1 }),
+        unique(doc! { "MemberNumber": 1 }),
+        // Emails are stored normalized, only non-empty ones have to be unique
+        IndexModel::builder()
+            .keys(doc! { "Email": 1 })
+            .options(
+                IndexOptions::builder()
+                    .unique(true)
+                    .partial_filter_expression(doc! { "Email": { "$gt": "" } })
+                    .build(),
+            )
+            .build(),
+    ]
+}
+
+fn index_options_match(current: &IndexModel, required: &IndexModel) -> bool {
+    let options = |index: &IndexModel| {
+        let options = index.options.clone().unwrap_or_default();
+        (
+            options.unique.unwrap_or(false),
+            options.partial_filter_expression,
+            options.expire_after,
+        )
+    };
+    options(current) == options(required)
+}
+
+fn describe_index_options(index: &IndexModel) -> String {
+    let options = index.options.clone().unwrap_or_default();
+    let mut parts = vec![format!("unique: {}", options.unique.unwrap_or(false))];
+    if let Some(filter) = options.partial_filter_expression {
+        parts.push(format!("partial: {filter}"));
+    }
+    if let Some(expire_after) = options.expire_after {
+        parts.push(format!("expire after: {expire_after:?}"));
+    }
+    parts.join(", ")
+}
+
+#[async_trait]
+impl AccountStore for MongoAccountStore {
+    async fn ping(&self) -> Result<(), StoreError> {
+        self.db.run_command(doc! { "ping": 1 }, None).await?;
+        Ok(())
+    }
+
+    async fn find_by_name(&self, account_name: &str) -> Result<Option<Account>, StoreError> {
+        self.find_account(doc! { "AccountName": account_name })
+            .await
+    }
+
+    async fn find_by_member_number(
+        &self,
+        member_number: u32,
+    ) -> Result<Option<Account>, StoreError> {
+        self.find_account(doc! { "MemberNumber": member_number })
+            .await
+    }
+
+    async fn find_by_email(&self, email: &str) -> Result<Option<Account>, StoreError> {
+        // An exact match uses the unique index, stored emails are normalized by the migration
+        self.find_account(doc! { "Email": Account::normalize_mail(email) })
+            .await
+    }
+
+    async fn insert(&self, account: &Account) -> Result<(), StoreError> {
+        self.accounts.insert_one(account, None).await?;
+        Ok(())
+    }
+
+    async fn update(&self, account_name: &str, patch: AccountPatch) -> Result<(), StoreError> {
+        let update = bson::to_document(&patch).map_err(|e| StoreError::Backend(e.to_string()))?;
+        let result = self
+            .accounts
+            .update_one(
+                doc! { "AccountName": account_name },
+                doc! { "$set": update },
+                None,
+            )
+            .await?;
+        if result.matched_count == 0 {
+            return Err(StoreError::NotFound(account_name.to_string()));
+        }
+        Ok(())
+    }
+
+    async fn next_member_number(&self) -> Result<u32, StoreError> {
+        let options = FindOneAndUpdateOptions::builder()
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .build();
+        // Two servers creating the counter at once makes one upsert fail, the retry finds it
+        let mut attempts = 0;
+        let counter = loop {
+            let result = self
+                .counters
+                .find_one_and_update(
+                    doc! { "_id": MEMBER_NUMBER_COUNTER },
+                    doc! { "$inc": { "Value": 1 } },
+                    options.clone(),
+                )
+                .await
+                .map_err(StoreError::from);
+            match result {
+                Err(StoreError::Duplicate(_)) if attempts < 3 => attempts += 1,
+                result => break result?,
+            }
+        };
+        counter
+            .as_ref()
+            .and_then(|c| c.get("Value"))
+            .and_then(bson_as_u32)
+            .ok_or_else(|| backend_error("member number counter is not a number"))
+    }
+
+    async fn list_page(
+        &self,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<AccountPage, StoreError> {
+        // Read raw documents so a single broken account doesn't stop the whole listing
+        let accounts = self.documents();
+        let options = FindOptions::builder()
+            .sort(doc! { "AccountName": 1 })
+            .limit(limit as i64)
+            .build();
+        let mut cursor = accounts
+            .find(
+                doc! { "AccountName": { "$gt": after.unwrap_or_default() } },
+                options,
+            )
+            .await?;
+
+        let mut page = AccountPage::default();
+        while cursor.advance().await? {
+            let doc = cursor.deserialize_current()?;
+            let account_name = doc.get_str("AccountName").unwrap_or_default().to_string();
+            match account_from_document(doc) {
+                Ok(account) => page.accounts.push(account),
+                Err(e) => {
+                    warn!(account_name, "skipping unreadable account: {e}");
+                    page.unreadable.push(account_name.clone());
+                }
+            }
+            page.last = Some(account_name);
+        }
+        Ok(page)
+    }
+
+    async fn migrate_accounts(&self, dry_run: bool) -> Result<MigrationReport, StoreError> {
+        let accounts = self.documents();
+        let mut report = MigrationReport {
+            dry_run,
+            ..Default::default()
+        };
+
+        let options = FindOptions::builder()
+            .projection(doc! { "AccountName": 1, "Email": 1 })
+            .build();
+        let mut cursor = accounts
+            .find(doc! { "Email": { "$gt": "" } }, options)
+            .await?;
+        let mut emails = Vec::new();
+        while cursor.advance().await? {
+            let doc = cursor.deserialize_current()?;
+            if let (Ok(account_name), Ok(email)) =
+                (doc.get_str("AccountName"), doc.get_str("Email"))
+            {
+                emails.push((account_name.to_string(), email.to_string()));
+            }
+        }
+        report.find_duplicate_emails(emails);
+
+        let mut cursor = accounts.find(None, None).await?;
+        while cursor.advance().await? {
+            let doc = cursor.deserialize_current()?;
+            let account_name = doc.get_str("AccountName").unwrap_or_default().to_string();
+            let Some(id) = doc.get("_id").cloned() else {
+                continue;
+            };
+            if report.has_duplicate_email(&account_name) {
+                report.scanned += 1;
+                continue;
+            }
+            let original: Map<String, Value> = match bson::from_document(doc) {
+                Ok(fields) => fields,
+                Err(_) => {
+                    report.scanned += 1;
+                    report.failed.push(account_name);
+                    continue;
+                }
+            };
+            let mut fields = original.clone();
+            let changed = migrate_account(&mut fields);
+            report.record(changed.as_deref());
+            if dry_run || changed.is_none() {
+                continue;
+            }
+
+            // Only touch the fields the migrations changed, the rest keeps its BSON types
+            let mut set = Document::new();
+            for (key, value) in &fields {
+                if original.get(key) != Some(value) {
+                    set.insert(key, bson::to_bson(value).map_err(backend_error)?);
+                }
+            }
+            let mut unset = Document::new();
+            for key in original.keys().filter(|k| !fields.contains_key(*k)) {
+                unset.insert(key, "");
+            }
+            let mut update = doc! { "$set": set };
+            if !unset.is_empty() {
+                update.insert("$unset", unset);
+            }
+            if let Err(e) = accounts.update_one(doc! { "_id": id }, update, None).await {
+                error!(account_name, "failed to migrate account: {e}");
+                report.failed.push(account_name);
+            }
+        }
+        Ok(report)
+    }
+}
@@ -0,0 +1,670 @@
+use async_trait::async_trait;
+use rusqlite::{
+    Connection, ErrorCode, OptionalExtension, params_from_iter,
+    types::{Value as SqlValue, ValueRef},
+};
+use serde_json::{Map, Value};
+use std::sync::{Arc, Mutex};
+use tracing::warn;
+
+use crate::{
+    config::AppConfig,
+    models::{
+        account::Account,
+        account_migrations::{MigrationReport, migrate_account},
+    },
+    storage::{AccountPage, AccountPatch, AccountStore, StoreError, mongo::MongoAccountStore},
+};
+
+#[derive(Clone, Copy)]
+enum Column {
+    Text,
+    Integer,
+    Bool,
+    /// Anything that isn't a scalar is kept as JSON text
+    Json,
+}
+
+/// Serialized account field, SQL column, column type
+const COLUMNS: &[(&str, &str, Column)] = &[
+    ("AccountName", "account_name", Column::Text),
+    ("MemberNumber", "member_number", Column::Integer),
+    ("Name", "name", Column::Text),
+    ("Password", "password", Column::Text),
+    ("Email", "email", Column::Text),
+    ("EmailVerified", "email_verified", Column::Bool),
+    ("ItemPermission", "item_permission", Column::Integer),
+    ("Money", "money", Column::Integer),
+    ("Creation", "creation", Column::Integer),
+    ("LastLogin", "last_login", Column::Integer),
+    ("Environment", "environment", Column::Text),
+    ("Title", "title", Column::Text),
+    ("Description", "description", Column::Text),
+    ("Lover", "lover", Column::Text),
+    ("FriendList", "friend_list", Column::Json),
+    ("WhiteList", "white_list", Column::Json),
+    ("BlackList", "black_list", Column::Json),
+    ("ChatRoom", "chat_room", Column::Json),
+    ("Ownership", "ownership", Column::Json),
+    ("Lovership", "lovership", Column::Json),
+    ("InventoryData", "inventory_data", Column::Json),
+    ("ArousalSettings", "arousal_settings", Column::Json),
+    (
+        "OnlineSharedSettings",
+        "online_shared_settings",
+        Column::Json,
+    ),
+    ("Game", "game", Column::Json),
+    ("MapData", "map_data", Column::Json),
+    ("LabelColor", "label_color", Column::Json),
+    ("Appearance", "appearance", Column::Json),
+    ("Reputation", "reputation", Column::Json),
+    ("BlockItems", "block_items", Column::Json),
+    ("LimitedItems", "limited_items", Column::Json),
+    ("FavoriteItems", "favorite_items", Column::Json),
+    ("Skill", "skill", Column::Json),
+    ("Nickname", "nickname", Column::Json),
+    ("Crafting", "crafting", Column::Json),
+    ("Log", "log", Column::Json),
+    (
+        "DelayedAppearanceUpdate",
+        "delayed_appearance_update",
+        Column::Json,
+    ),
+    ("DelayedSkillUpdate", "delayed_skill_update", Column::Json),
+    ("DelayedGameUpdate", "delayed_game_update", Column::Json),
+    ("EmailVerification", "email_verification", Column::Json),
+    ("TwoFactor", "two_factor", Column::Json),
+];
+
+/// Fields without a column of their own end up in here, so nothing is lost
+const EXTRA_COLUMN: &str = "extra";
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(err: rusqlite::Error) -> Self {
+        match err.sqlite_error_code() {
+            Some(ErrorCode::ConstraintViolation) => StoreError::Duplicate(err.to_string()),
+            _ => StoreError::Backend(err.to_string()),
+        }
+    }
+}
+
+fn backend_error(err: impl ToString) -> StoreError {
+    StoreError::Backend(err.to_string())
+}
+
+fn create_schema(conn: &Connection) -> Result<(), StoreError> {
+    let columns: Vec<String> = COLUMNS
+        .iter()
+        .map(|(_, column, kind)| {
+            let sql_type = match kind {
+                Column::Text | Column::Json => "TEXT",
+                Column::Integer | Column::Bool => "INTEGER",
+            };
+            match *column {
+                "account_name" => format!("{column} {sql_type} PRIMARY KEY"),
+                "member_number" => format!("{column} {sql_type} NOT NULL UNIQUE"),
+                _ => format!("{column} {sql_type}"),
+            }
+        })
+        .collect();
+    conn.execute_batch(&format!(
+        "CREATE TABLE IF NOT EXISTS accounts ({}, {EXTRA_COLUMN} TEXT);
+         CREATE UNIQUE INDEX IF NOT EXISTS accounts_email ON accounts (email) WHERE email <> '';
+         CREATE TABLE IF NOT EXISTS counters (name TEXT PRIMARY KEY, value INTEGER NOT NULL);",
+        columns.join(", ")
+    ))?;
+    Ok(())
+}
+
+/// Splits a serialized account into the values of every column
+fn to_row(account: &Account) -> Result<Vec<SqlValue>, StoreError> {
+    let Value::Object(mut fields) = serde_json::to_value(account).map_err(backend_error)? else {
+        return Err(backend_error("account is not an object"));
+    };
+    // The session ID never goes to the database
+    fields.remove("ID");
+
+    let mut row = Vec::with_capacity(COLUMNS.len() + 1);
+    for (field, _, kind) in COLUMNS {
+        let value = fields.remove(*field).unwrap_or(Value::Null);
+        row.push(match (kind, value) {
+            (_, Value::Null) => SqlValue::Null,
+            (Column::Text, Value::String(s)) => SqlValue::Text(s),
+            (Column::Integer, Value::Number(n)) => {
+                SqlValue::Integer(n.as_i64().ok_or_else(|| backend_error("invalid integer"))?)
+            }
+            (Column::Bool, Value::Bool(b)) => SqlValue::Integer(b as i64),
+            (_, value) => SqlValue::Text(value.to_string()),
+        });
+    }
+    row.push(SqlValue::Text(Value::Object(fields).to_string()));
+    Ok(row)
+}
+
+fn corrupt_column(
+    index: usize,
+    err: impl std::error::Error + Send + Sync + 'static,
+) -> rusqlite::Error {
+    rusqlite::Error::FromSqlConversionFailure(index, rusqlite::types::Type::Text, Box::new(err))
+}
+
+/// Serialized account fields of a row, as they are stored. JSON that doesn't parse is an
+/// error, dropping it would lose the field at the next save.
+fn row_fields(row: &rusqlite::Row) -> Result<Map<String, Value>, rusqlite::Error> {
+    let mut fields = Map::new();
+    for (index, (field, _, kind)) in COLUMNS.iter().enumerate() {
+        let value = match (kind, row.get_ref(index)?) {
+            (_, ValueRef::Null) => Value::Null,
+            (Column::Bool, ValueRef::Integer(i)) => Value::Bool(i != 0),
+            (_, ValueRef::Integer(i)) => Value::from(i),
+            (_, ValueRef::Real(f)) => Value::from(f),
+            (Column::Json, ValueRef::Text(text)) => {
+                serde_json::from_slice(text).map_err(|e| corrupt_column(index, e))?
+            }
+            (_, ValueRef::Text(text)) => Value::String(String::from_utf8_lossy(text).into()),
+            (_, ValueRef::Blob(_)) => {
+                return Err(rusqlite::Error::InvalidColumnType(
+                    index,
+                    field.to_string(),
+                    rusqlite::types::Type::Blob,
+                ));
+            }
+        };
+        fields.insert(field.to_string(), value);
+    }
+    if let ValueRef::Text(extra) = row.get_ref(COLUMNS.len())? {
+        let extra: Map<String, Value> =
+            serde_json::from_slice(extra).map_err(|e| corrupt_column(COLUMNS.len(), e))?;
+        fields.extend(extra);
+    }
+    // Keep missing optional fields as `None` rather than failing on `null`
+    fields.retain(|_, v| !v.is_null());
+    Ok(fields)
+}
+
+/// Account name of a row, readable even when the rest of the row isn't
+fn row_account_name(row: &rusqlite::Row) -> Result<String, rusqlite::Error> {
+    // `account_name` is the first column
+    row.get(0)
+}
+
+fn account_from_fields(fields: Map<String, Value>) -> Result<Account, rusqlite::Error> {
+    serde_json::from_value(Value::Object(fields)).map_err(|e| corrupt_column(COLUMNS.len(), e))
+}
+
+/// Reads an account, upgrading older rows on the way
+fn from_row(row: &rusqlite::Row) -> Result<Account, rusqlite::Error> {
+    let mut fields = row_fields(row)?;
+    migrate_account(&mut fields);
+    account_from_fields(fields)
+}
+
+fn select_sql(filter: &str) -> String {
+    let columns: Vec<&str> = COLUMNS.iter().map(|(_, column, _)| *column).collect();
+    format!(
+        "SELECT {}, {EXTRA_COLUMN} FROM accounts {filter}",
+        columns.join(", ")
+    )
+}
+
+/// Overwrites every column of the account named by the last parameter
+fn update_sql() -> String {
+    let assignments: Vec<String> = COLUMNS
+        .iter()
+        .map(|(_, column, _)| format!("{column} = ?"))
+        .chain(std::iter::once(format!("{EXTRA_COLUMN} = ?")))
+        .collect();
+    format!(
+        "UPDATE accounts SET {} WHERE account_name = ?",
+        assignments.join(", ")
+    )
+}
+
+pub struct SqliteAccountStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteAccountStore {
+    pub fn open(path: &str) -> Result<Self, StoreError> {
+        let conn = Connection::open(path)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        create_schema(&conn)?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Runs blocking SQLite calls off the async runtime
+    async fn with_conn<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Connection) -> Result<T, StoreError> + Send + 'static,
+    ) -> Result<T, StoreError> {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = conn.lock().map_err(backend_error)?;
+            f(&mut conn)
+        })
+        .await
+        .map_err(backend_error)?
+    }
+
+    async fn find_one(
+        &self,
+        filter: &'static str,
+        param: SqlValue,
+    ) -> Result<Option<Account>, StoreError> {
+        self.with_conn(move |conn| {
+            Ok(conn
+                .query_row(&select_sql(filter), [param], from_row)
+                .optional()?)
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl AccountStore for SqliteAccountStore {
+    async fn ping(&self) -> Result<(), StoreError> {
+        self.with_conn(|conn| Ok(conn.execute_batch("SELECT 1")?))
+            .await
+    }
+
+    async fn find_by_name(&self, account_name: &str) -> Result<Option<Account>, StoreError> {
+        self.find_one(
+            "WHERE account_name = ?1",
+            SqlValue::Text(account_name.to_string()),
+        )
+        .await
+    }
+
+    async fn find_by_member_number(
+        &self,
+        member_number: u32,
+    ) -> Result<Option<Account>, StoreError> {
+        self.find_one(
+            "WHERE member_number = ?1",
+            SqlValue::Integer(member_number as i64),
+        )
+        .await
+    }
+
+    async fn find_by_email(&self, email: &str) -> Result<Option<Account>, StoreError> {
+        // An exact match uses the email index, stored emails are normalized by the migration
+        self.find_one(
+            "WHERE email = ?1",
+            SqlValue::Text(Account::normalize_mail(email)),
+        )
+        .await
+    }
+
+    async fn insert(&self, account: &Account) -> Result<(), StoreError> {
+        let row = to_row(account)?;
+        self.with_conn(move |conn| {
+            let placeholders = vec!["?"; row.len()].join(", ");
+            let columns: Vec<&str> = COLUMNS.iter().map(|(_, column, _)| *column).collect();
+            conn.execute(
+                &format!(
+                    "INSERT INTO accounts ({}, {EXTRA_COLUMN}) VALUES ({placeholders})",
+                    columns.join(", ")
+                ),
+                params_from_iter(row),
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn update(&self, account_name: &str, patch: AccountPatch) -> Result<(), StoreError> {
+        let account_name = account_name.to_string();
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            let account = tx
+                .query_row(
+                    &select_sql("WHERE account_name = ?1"),
+                    [&account_name],
+                    from_row,
+                )
+                .optional()?;
+            let Some(account) = account else {
+                return Err(StoreError::NotFound(account_name));
+            };
+
+            // Same as the other backends, patches apply to the serialized account
+            let mut value = serde_json::to_value(&account).map_err(backend_error)?;
+            let Some(fields) = value.as_object_mut() else {
+                return Err(backend_error("account is not an object"));
+            };
+            fields.extend(patch);
+            let account: Account = serde_json::from_value(value).map_err(backend_error)?;
+
+            let mut row = to_row(&account)?;
+            row.push(SqlValue::Text(account_name));
+            tx.execute(&update_sql(), params_from_iter(row))?;
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn next_member_number(&self) -> Result<u32, StoreError> {
+        self.with_conn(|conn| {
+            // Never goes below the accounts already there, copied ones included
+            let next: i64 = conn.query_row(
+                "INSERT INTO counters (name, value)
+                 VALUES ('MemberNumber', COALESCE((SELECT MAX(member_number) FROM accounts), 0) + 1)
+                 ON CONFLICT (name) DO UPDATE SET
+                   value = MAX(value, COALESCE((SELECT MAX(member_number) FROM accounts), 0)) + 1
+                 RETURNING value",
+                [],
+                |row| row.get(0),
+            )?;
+            Ok(next as u32)
+        })
+        .await
+    }
+
+    async fn list_page(
+        &self,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<AccountPage, StoreError> {
+        self.with_conn(move |conn| {
+            let mut statement = conn.prepare(&select_sql(
+                "WHERE account_name > ?1 ORDER BY account_name LIMIT ?2",
+            ))?;
+            let mut rows =
+                statement.query(rusqlite::params![after.unwrap_or_default(), limit as i64])?;
+            let mut page = AccountPage::default();
+            while let Some(row) = rows.next()? {
+                let account_name = row_account_name(row)?;
+                match from_row(row) {
+                    Ok(account) => page.accounts.push(account),
+                    Err(e) => {
+                        warn!(account_name, "skipping unreadable account: {e}");
+                        page.unreadable.push(account_name.clone());
+                    }
+                }
+                page.last = Some(account_name);
+            }
+            Ok(page)
+        })
+        .await
+    }
+
+    async fn migrate_accounts(&self, dry_run: bool) -> Result<MigrationReport, StoreError> {
+        self.with_conn(move |conn| {
+            let mut report = MigrationReport {
+                dry_run,
+                ..Default::default()
+            };
+            let tx = conn.transaction()?;
+            let rows = tx
+                .prepare(&select_sql(""))?
+                .query_map([], |row| Ok((row_account_name(row)?, row_fields(row))))?
+                .collect::<Result<Vec<_>, _>>()?;
+            report.find_duplicate_emails(rows.iter().filter_map(|(account_name, fields)| {
+                let email = fields.as_ref().ok()?.get("Email")?.as_str()?;
+                Some((account_name.clone(), email.to_string()))
+            }));
+            for (account_name, fields) in rows {
+                let Ok(mut fields) = fields else {
+                    report.scanned += 1;
+                    report.failed.push(account_name);
+                    continue;
+                };
+                if report.has_duplicate_email(&account_name) {
+                    report.scanned += 1;
+                    continue;
+                }
+                let changed = migrate_account(&mut fields);
+                report.record(changed.as_deref());
+                if dry_run || changed.is_none() {
+                    continue;
+                }
+                let row = account_from_fields(fields)
+                    .map_err(StoreError::from)
+                    .and_then(|account| to_row(&account));
+                match row {
+                    Ok(mut row) => {
+                        row.push(SqlValue::Text(account_name.clone()));
+                        if let Err(e) = tx.execute(&update_sql(), params_from_iter(row)) {
+                            warn!(account_name, "failed to migrate account: {e}");
+                            report.failed.push(account_name);
+                        }
+                    }
+                    Err(_) => report.failed.push(account_name),
+                }
+            }
+            tx.commit()?;
+            Ok(report)
+        })
+        .await
+    }
+}
+
+/// One-shot copy of the MongoDB accounts collection into the configured SQLite database.
+/// Fails after the copy if any account was left behind.
+pub async fn migrate_mongo_to_sqlite(config: &AppConfig) -> Result<(), StoreError> {
+    let source = MongoAccountStore::connect(config).await?;
+    let target = SqliteAccountStore::open(&config.sqlite_path)?;
+    copy_accounts(&source, &target, &config.sqlite_path).await
+}
+
+async fn copy_accounts(
+    source: &dyn AccountStore,
+    target: &dyn AccountStore,
+    target_name: &str,
+) -> Result<(), StoreError> {
+    let (mut copied, mut present) = (0, 0);
+    let mut skipped = vec![];
+    let mut after = None;
+    loop {
+        let page = source.list_page(after.clone(), 500).await?;
+        if page.last.is_none() {
+            break;
+        }
+        after = page.last;
+        skipped.extend(page.unreadable);
+        for account in page.accounts {
+            match target.insert(&account).await {
+                Ok(()) => copied += 1,
+                // Copied by an earlier run
+                Err(StoreError::Duplicate(_))
+                    if target.find_by_name(&account.account_name).await?.is_some() =>
+                {
+                    present += 1
+                }
+                Err(e) => {
+                    warn!(account_name = %account.account_name, "skipping account: {e}");
+                    skipped.push(account.account_name);
+                }
+            }
+        }
+    }
+    println!(
+        "Copied {copied} accounts into {target_name}, {present} were there already, skipped {}",
+        skipped.len()
+    );
+    if !skipped.is_empty() {
+        println!("  Skipped: {}", skipped.join(", "));
+        return Err(StoreError::Backend(format!(
+            "{} accounts were not copied",
+            skipped.len()
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::account_migrations::CURRENT_SCHEMA_VERSION, storage::contract::store_contract_tests,
+    };
+
+    store_contract_tests!(SqliteAccountStore::open(":memory:").unwrap());
+
+    /// Three accounts, with the JSON of the middle one broken
+    async fn store_with_corrupt_account() -> SqliteAccountStore {
+        let store = SqliteAccountStore::open(":memory:").unwrap();
+        for (member_number, name) in ["ALICE", "BOB", "CAROL"].iter().enumerate() {
+            let account = Account {
+                account_name: name.to_string(),
+                member_number: member_number as u32 + 1,
+                ..Default::default()
+            };
+            store.insert(&account).await.unwrap();
+        }
+        store
+            .conn
+            .lock()
+            .unwrap()
+            .execute(
+                "UPDATE accounts SET appearance = '[{broken' WHERE account_name = 'BOB'",
+                [],
+            )
+            .unwrap();
+        store
+    }
+
+    #[tokio::test]
+    async fn corrupt_json_is_an_error_not_a_missing_field() {
+        let store = store_with_corrupt_account().await;
+        assert!(store.find_by_name("BOB").await.is_err());
+        assert!(store.find_by_name("ALICE").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn list_page_reports_unreadable_accounts_and_goes_on() {
+        let store = store_with_corrupt_account().await;
+
+        let page = store.list_page(Some("ALICE".to_string()), 1).await.unwrap();
+        assert!(page.accounts.is_empty());
+        assert_eq!(page.unreadable, ["BOB"]);
+        assert_eq!(page.last.as_deref(), Some("BOB"));
+
+        let page = store.list_page(page.last, 1).await.unwrap();
+        assert_eq!(page.accounts[0].account_name, "CAROL");
+        assert!(page.unreadable.is_empty());
+    }
+
+    #[tokio::test]
+    async fn copy_reports_unreadable_accounts() {
+        let source = store_with_corrupt_account().await;
+        let target = crate::storage::memory::MemoryAccountStore::new();
+
+        assert!(copy_accounts(&source, &target, "memory").await.is_err());
+        // Everything readable still made it, past the broken account
+        assert!(target.find_by_name("ALICE").await.unwrap().is_some());
+        assert!(target.find_by_name("CAROL").await.unwrap().is_some());
+        assert!(target.find_by_name("BOB").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn copy_can_be_rerun() {
+        let source = SqliteAccountStore::open(":memory:").unwrap();
+        let account = Account {
+            account_name: "ALICE".to_string(),
+            member_number: 1,
+            ..Default::default()
+        };
+        source.insert(&account).await.unwrap();
+        let target = crate::storage::memory::MemoryAccountStore::new();
+
+        copy_accounts(&source, &target, "memory").await.unwrap();
+        copy_accounts(&source, &target, "memory").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn migration_upgrades_each_account_once() {
+        let store = SqliteAccountStore::open(":memory:").unwrap();
+        let account = Account {
+            account_name: "ALICE".to_string(),
+            member_number: 1,
+            email: Some(" Alice@Example.com".to_string()),
+            ..Default::default()
+        };
+        store.insert(&account).await.unwrap();
+
+        let report = store.migrate_accounts(false).await.unwrap();
+        assert_eq!((report.scanned, report.outdated), (1, 1));
+        let stored = store.find_by_name("ALICE").await.unwrap().unwrap();
+        assert_eq!(stored.schema_version, CURRENT_SCHEMA_VERSION);
+        assert_eq!(stored.email.as_deref(), Some("alice@example.com"));
+
+        let report = store.migrate_accounts(false).await.unwrap();
+        assert_eq!((report.scanned, report.outdated), (1, 0));
+        assert!(report.steps.is_empty());
+    }
+
+    #[tokio::test]
+    async fn migration_leaves_accounts_sharing_an_email_alone() {
+        let store = SqliteAccountStore::open(":memory:").unwrap();
+        for (member_number, (name, email)) in [
+            ("ALICE", "Alice@Example.com"),
+            ("BOB", "alice@example.com "),
+            ("CAROL", "Carol@Example.com"),
+        ]
+        .iter()
+        .enumerate()
+        {
+            let account = Account {
+                account_name: name.to_string(),
+                member_number: member_number as u32 + 1,
+                email: Some(email.to_string()),
+                ..Default::default()
+            };
+            store.insert(&account).await.unwrap();
+        }
+
+        let report = store.migrate_accounts(false).await.unwrap();
+        assert_eq!(report.scanned, 3);
+        assert!(report.failed.is_empty());
+        assert_eq!(
+            report.duplicate_emails.get("alice@example.com"),
+            Some(&vec!["ALICE".to_string(), "BOB".to_string()])
+        );
+        // Read the rows as stored, loading them would upgrade them in memory
+        let stored = |account_name: &str| -> (String, i64) {
+            store
+                .conn
+                .lock()
+                .unwrap()
+                .query_row(
+                    "SELECT email, extra ->> 'SchemaVersion' FROM accounts WHERE account_name = ?1",
+                    [account_name],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .unwrap()
+        };
+        assert_eq!(stored("ALICE"), ("Alice@Example.com".to_string(), 0));
+        assert_eq!(
+            stored("CAROL"),
+            (
+                "carol@example.com".to_string(),
+                CURRENT_SCHEMA_VERSION as i64
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn export_reports_unreadable_accounts() {
+        let store = store_with_corrupt_account().await;
+        let mut output = vec![];
+        let report = crate::backup::export_accounts(&store, &mut output, Default::default())
+            .await
+            .unwrap();
+        assert_eq!(report.exported, 2);
+        assert_eq!(report.unreadable, ["BOB"]);
+        assert_eq!(String::from_utf8(output).unwrap().lines().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn migration_reports_unreadable_accounts() {
+        let store = store_with_corrupt_account().await;
+        let report = store.migrate_accounts(false).await.unwrap();
+        assert_eq!(report.scanned, 3);
+        assert_eq!(report.failed, ["BOB"]);
+    }
+}
@@ -0,0 +1,161 @@
+use axum::{
+    Router,
+    extract::Request,
+    http::{StatusCode, header},
+    response::{IntoResponse, Redirect},
+};
+use axum_server::tls_rustls::RustlsConfig;
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use tracing::{info, warn};
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+pub async fn load_tls_config(cert_path: &str, key_path: &str) -> io::Result<RustlsConfig> {
+    // rustls needs a crypto provider for the whole process, lettre already pulls in ring
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    RustlsConfig::from_pem_file(cert_path, key_path)
+        .await
+        .map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("failed to load the TLS certificate {cert_path} or key {key_path}: {e}"),
+            )
+        })
+}
+
+/// Reloads the certificate whenever one of the files changes, so renewals don't need a restart
+pub fn spawn_certificate_reload(
+    tls_config: RustlsConfig,
+    cert_path: String,
+    key_path: String,
+    interval: Duration,
+) {
+    tokio::spawn(async move {
+        let cert_path = PathBuf::from(cert_path);
+        let key_path = PathBuf::from(key_path);
+        let mut last_modified = (modified(&cert_path), modified(&key_path));
+        loop {
+            tokio::time::sleep(interval).await;
+            let current = (modified(&cert_path), modified(&key_path));
+            if current == last_modified {
+                continue;
+            }
+            match tls_config.reload_from_pem_file(&cert_path, &key_path).await {
+                Ok(_) => {
+                    info!("TLS certificate reloaded");
+                    last_modified = current;
+                }
+                // Certbot and friends write both files one after the other, try again next time
+                Err(e) => warn!("failed to reload TLS certificate: {e}"),
+            }
+        }
+    });
+}
+
+/// Plain HTTP router sending everyone to the HTTPS listener
+pub fn redirect_router(https_port: u16) -> Router {
+    Router::new().fallback(move |request: Request| async move {
+        let Some(host) = request
+            .headers()
+            .get(header::HOST)
+            .and_then(|h| h.to_str().ok())
+        else {
+            return StatusCode::BAD_REQUEST.into_response();
+        };
+        // Drop the port of the plain listener, and add the HTTPS one unless it's the default
+        let host = match host.rsplit_once(':') {
+            Some((name, port)) if port.parse::<u16>().is_ok() => name,
+            _ => host,
+        };
+        let authority = if https_port == 443 {
+            host.to_string()
+        } else {
+            format!("{host}:{https_port}")
+        };
+        let path = request
+            .uri()
+            .path_and_query()
+            .map(|p| p.as_str())
+            .unwrap_or("/");
+        Redirect::permanent(&format!("https://{authority}{path}")).into_response()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use tower::ServiceExt;
+
+    /// Where a request to the plain listener is sent, `None` when it isn't redirected
+    async fn redirect(https_port: u16, host: Option<&str>, uri: &str) -> Option<String> {
+        let mut request = Request::builder().uri(uri);
+        if let Some(host) = host {
+            request = request.header(header::HOST, host);
+        }
+        let response = redirect_router(https_port)
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        if response.status() != StatusCode::PERMANENT_REDIRECT {
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            return None;
+        }
+        response
+            .headers()
+            .get(header::LOCATION)
+            .and_then(|l| l.to_str().ok())
+            .map(str::to_string)
+    }
+
+    #[tokio::test]
+    async fn host_port_is_replaced_by_the_https_one() {
+        for (https_port, host, expected) in [
+            (443, "example.com", "https://example.com/"),
+            (443, "example.com:80", "https://example.com/"),
+            (443, "example.com:8080", "https://example.com/"),
+            (4288, "example.com", "https://example.com:4288/"),
+            (4288, "example.com:80", "https://example.com:4288/"),
+            (8443, "[::1]:80", "https://[::1]:8443/"),
+            (443, "[::1]", "https://[::1]/"),
+        ] {
+            assert_eq!(
+                redirect(https_port, Some(host), "/").await.as_deref(),
+                Some(expected),
+                "{host} to port {https_port}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn path_and_query_are_kept() {
+        assert_eq!(
+            redirect(
+                443,
+                Some("example.com"),
+                "/socket.io/?EIO=4&transport=polling"
+            )
+            .await
+            .as_deref(),
+            Some("https://example.com/socket.io/?EIO=4&transport=polling")
+        );
+        assert_eq!(
+            redirect(8443, Some("example.com:80"), "/readyz")
+                .await
+                .as_deref(),
+            Some("https://example.com:8443/readyz")
+        );
+    }
+
+    #[tokio::test]
+    async fn requests_without_a_host_are_refused() {
+        assert_eq!(redirect(443, None, "/").await, None);
+    }
+}
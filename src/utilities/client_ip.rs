@@ -0,0 +1,226 @@
+use axum::http::HeaderMap;
+use ipnet::IpNet;
+use serde::Deserialize;
+use std::net::IpAddr;
+
+/// Header holding the real client address when running behind a reverse proxy
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ClientIpHeader {
+    /// Not behind a proxy, the TCP peer is the client
+    #[default]
+    None,
+    XForwardedFor,
+    XRealIp,
+    CfConnectingIp,
+}
+
+/// The only place client addresses come from. Headers are ignored unless the TCP peer is a
+/// trusted proxy, so players can't spoof their address to dodge rate limits.
+#[derive(Debug, Clone)]
+pub struct ClientIpResolver {
+    header: ClientIpHeader,
+    trusted_proxies: Vec<IpNet>,
+}
+
+impl ClientIpResolver {
+    pub fn new(header: ClientIpHeader, trusted_proxies: Vec<IpNet>) -> Self {
+        Self {
+            header,
+            trusted_proxies,
+        }
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(&ip))
+    }
+
+    fn header_ip(headers: &HeaderMap, name: &str) -> Option<IpAddr> {
+        headers
+            .get(name)?
+            .to_str()
+            .ok()?
+            .trim()
+            .parse::<IpAddr>()
+            .ok()
+            .map(|ip| ip.to_canonical())
+    }
+
+    pub fn resolve(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let peer = peer.to_canonical();
+        if !self.is_trusted(peer) {
+            return peer;
+        }
+        let resolved = match self.header {
+            ClientIpHeader::None => None,
+            ClientIpHeader::XRealIp => Self::header_ip(headers, "x-real-ip"),
+            ClientIpHeader::CfConnectingIp => Self::header_ip(headers, "cf-connecting-ip"),
+            ClientIpHeader::XForwardedFor => {
+                // Every proxy appends the address it got the request from, so the client is the
+                // rightmost address that isn't one of our proxies
+                let forwarded: Vec<IpAddr> = headers
+                    .get_all("x-forwarded-for")
+                    .iter()
+                    .filter_map(|h| h.to_str().ok())
+                    .flat_map(|h| h.split(','))
+                    .filter_map(|ip| ip.trim().parse::<IpAddr>().ok())
+                    .map(|ip| ip.to_canonical())
+                    .collect();
+                forwarded
+                    .iter()
+                    .rev()
+                    .find(|ip| !self.is_trusted(**ip))
+                    .or(forwarded.first())
+                    .copied()
+            }
+        };
+        resolved.unwrap_or(peer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn resolver(header: ClientIpHeader) -> ClientIpResolver {
+        ClientIpResolver::new(
+            header,
+            vec!["10.0.0.0/8".parse().unwrap(), "::1/128".parse().unwrap()],
+        )
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    /// Name, header kind, peer, request headers and the expected client address
+    type Case = (
+        &'static str,
+        ClientIpHeader,
+        &'static str,
+        &'static [(&'static str, &'static str)],
+        &'static str,
+    );
+
+    #[test]
+    fn resolve() {
+        let cases: &[Case] = &[
+            (
+                "untrusted peer, no header",
+                ClientIpHeader::XForwardedFor,
+                "203.0.113.7",
+                &[],
+                "203.0.113.7",
+            ),
+            (
+                "spoofed forwarded-for from an untrusted peer",
+                ClientIpHeader::XForwardedFor,
+                "203.0.113.7",
+                &[("x-forwarded-for", "198.51.100.1")],
+                "203.0.113.7",
+            ),
+            (
+                "spoofed real-ip from an untrusted peer",
+                ClientIpHeader::XRealIp,
+                "203.0.113.7",
+                &[("x-real-ip", "198.51.100.1")],
+                "203.0.113.7",
+            ),
+            (
+                "trusted peer without header falls back to the peer",
+                ClientIpHeader::XForwardedFor,
+                "10.1.2.3",
+                &[],
+                "10.1.2.3",
+            ),
+            (
+                "trusted peer with an unparsable header falls back to the peer",
+                ClientIpHeader::XRealIp,
+                "10.1.2.3",
+                &[("x-real-ip", "not an ip")],
+                "10.1.2.3",
+            ),
+            (
+                "header kind none ignores every header",
+                ClientIpHeader::None,
+                "10.1.2.3",
+                &[("x-forwarded-for", "198.51.100.1")],
+                "10.1.2.3",
+            ),
+            (
+                "trusted peer, single forwarded-for",
+                ClientIpHeader::XForwardedFor,
+                "10.1.2.3",
+                &[("x-forwarded-for", "198.51.100.1")],
+                "198.51.100.1",
+            ),
+            (
+                "forwarded-for walk skips trusted proxies from the right",
+                ClientIpHeader::XForwardedFor,
+                "10.1.2.3",
+                &[("x-forwarded-for", "192.0.2.9, 198.51.100.1, 10.4.5.6")],
+                "198.51.100.1",
+            ),
+            (
+                "forwarded-for walk spans repeated headers",
+                ClientIpHeader::XForwardedFor,
+                "10.1.2.3",
+                &[
+                    ("x-forwarded-for", "192.0.2.9"),
+                    ("x-forwarded-for", "198.51.100.1, 10.4.5.6"),
+                ],
+                "198.51.100.1",
+            ),
+            (
+                "client spoofing a leftmost entry only gets its real address",
+                ClientIpHeader::XForwardedFor,
+                "10.1.2.3",
+                &[("x-forwarded-for", "1.2.3.4, 198.51.100.1")],
+                "198.51.100.1",
+            ),
+            (
+                "only trusted proxies forwarded, the leftmost one wins",
+                ClientIpHeader::XForwardedFor,
+                "10.1.2.3",
+                &[("x-forwarded-for", "10.7.7.7, 10.4.5.6")],
+                "10.7.7.7",
+            ),
+            (
+                "trusted peer with real-ip",
+                ClientIpHeader::XRealIp,
+                "10.1.2.3",
+                &[("x-real-ip", " 198.51.100.1 ")],
+                "198.51.100.1",
+            ),
+            (
+                "trusted peer with cf-connecting-ip",
+                ClientIpHeader::CfConnectingIp,
+                "10.1.2.3",
+                &[("cf-connecting-ip", "2001:db8::1")],
+                "2001:db8::1",
+            ),
+            (
+                "ipv4-mapped trusted peer is canonicalized",
+                ClientIpHeader::XRealIp,
+                "::ffff:10.1.2.3",
+                &[("x-real-ip", "::ffff:198.51.100.1")],
+                "198.51.100.1",
+            ),
+            (
+                "trusted ipv6 peer",
+                ClientIpHeader::XForwardedFor,
+                "::1",
+                &[("x-forwarded-for", "198.51.100.1")],
+                "198.51.100.1",
+            ),
+        ];
+        for (name, header, peer, pairs, expected) in cases {
+            let resolved = resolver(*header).resolve(peer.parse().unwrap(), &headers(pairs));
+            assert_eq!(resolved, expected.parse::<IpAddr>().unwrap(), "{name}");
+        }
+    }
+}
@@ -0,0 +1,19 @@
+use std::{collections::HashMap, sync::Arc};
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+/// One mutex per key, created on first use and dropped once nobody holds or waits for it
+#[derive(Default)]
+pub struct KeyedMutex {
+    locks: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+}
+
+impl KeyedMutex {
+    pub async fn lock(&self, key: &str) -> OwnedMutexGuard<()> {
+        let lock = {
+            let mut locks = self.locks.lock().await;
+            locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+            locks.entry(key.to_string()).or_default().clone()
+        };
+        lock.lock_owned().await
+    }
+}
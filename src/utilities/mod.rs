@@ -1 +1,3 @@
+pub mod client_ip;
+pub mod keyed_mutex;
 pub mod millis_timestamps;